@@ -11,21 +11,32 @@ import glob
 import random
 import json
 import os
+import re
 import sqlite3
 import subprocess
 import sys
 import time
+import urllib.parse
 from datetime import datetime, timedelta, timezone
 
+from . import _ccnotify
+
 VERSION = "1.2.0"
 
 PREVIEW_ROWS = 7  # lines reserved for inline preview (divider + header + content)
 
 DB_PATH = os.environ.get("AGENT_TOP_DB") or os.path.expanduser("~/.claude/ccnotify/ccnotify.db")
+# The bundled ccnotify module shares the installed hook's config.json (redaction patterns etc.)
+_ccnotify.CONFIG_PATH = os.environ.get("CCNOTIFY_CONFIG") or os.path.join(os.path.dirname(DB_PATH), "config.json")
+_ccnotify.RETENTION_DEFAULTS["archive_dir"] = os.path.join(os.path.dirname(DB_PATH), "archive")
 
 MAX_COMPLETED_AGENTS = 10
 MAX_HISTORY = 20
 MAX_TOOL_EVENTS = 3  # tools shown per agentless session
+MAX_NOTIFICATIONS = 100
+MAX_ATTENTION = 5  # rows in the ATTENTION panel
+# agent-top never writes the database, so the notification read marker lives here
+READ_STATE_PATH = os.environ.get("AGENT_TOP_STATE") or os.path.expanduser("~/.claude/ccnotify/agent-top-state.json")
 
 # ── CONSTANTS & SYMBOLS ──────────────────────────────────────
 
@@ -60,8 +71,8 @@ TOOL_NAMES = {
     "Skill": "Run skill",
 }
 
-VIZ_MODES = ["tree", "gantt"]
-VIZ_LABELS = {"tree": "TREE", "gantt": "TIMELINE"}
+VIZ_MODES = ["tree", "gantt", "notifications", "files", "changes"]
+VIZ_LABELS = {"tree": "TREE", "gantt": "TIMELINE", "notifications": "NOTIFICATIONS", "files": "FILES", "changes": "CHANGES"}
 
 
 def friendly_tool(name: str, label: str = "") -> str:
@@ -145,7 +156,155 @@ STATS_RANGES = [
 ]
 
 
-def query_db(db_path: str, stats_range_idx: int = 2) -> dict:
+USAGE_TOKENS_SQL = "input_tokens + output_tokens + cache_write_tokens + cache_read_tokens"
+SESSION_CWD_SQL = "SELECT p.cwd FROM prompt p WHERE p.session_id = te.session_id ORDER BY p.id LIMIT 1"
+
+# prompt columns ccnotify fills from git and the hook payload
+SESSION_CONTEXT_KEYS = ("git_root", "git_branch", "git_commit", "git_dirty", "model", "permission_mode", "source")
+# ...and what query_db adds from session_state
+SESSION_STATE_KEYS = ("state", "state_since", "state_totals")
+
+# STATS grouping (`s`): label, and the prompt column to group by — None is the project (cwd)
+STATS_GROUPS = [
+    ("project", None),
+    ("branch", "git_branch"),
+    ("model", "model"),
+    ("mode", "permission_mode"),
+]
+
+
+def session_attr_sql(column: str, sid_expr: str) -> str:
+    """Subquery for a session's latest non-null prompt.<column>."""
+    return (f"SELECT p.{column} FROM prompt p WHERE p.session_id = {sid_expr} AND p.{column} IS NOT NULL "
+            "ORDER BY p.id DESC LIMIT 1")
+
+
+def connect_ro(db_path: str | None = None) -> sqlite3.Connection:
+    """Read-only connection — safe on shared, copied or archived databases."""
+    path = os.path.abspath(db_path or DB_PATH)
+    return sqlite3.connect(f"file:{urllib.parse.quote(path)}?mode=ro", uri=True)
+
+
+def _query_session_detail(conn, sids: list[str], data: dict) -> None:
+    """Per-session rows the TREE, TIMELINE, FILES and CHANGES views draw from, for the given sessions."""
+    # Session tools: extended tool list per session (for DETAIL view)
+    for sid in sids:
+        try:
+            rows = []
+            for row in conn.execute(
+                """SELECT tool_name, tool_label, created_at, tool_input, tool_response, duration_ms, is_error, error_message, cwd, redactions,
+                          tool_use_id, agent_id FROM tool_event
+                   WHERE session_id = ?
+                   ORDER BY created_at DESC
+                   LIMIT 200""",
+                (sid,),
+            ):
+                rows.append(dict(row))
+            if rows:
+                data["session_tools"][sid] = list(reversed(rows))
+        except sqlite3.OperationalError:
+            pass
+
+    # Session prompts: all prompts per session (for interleaved tree view)
+    for sid in sids:
+        try:
+            rows = []
+            for row in conn.execute(
+                """SELECT prompt, created_at FROM prompt
+                   WHERE session_id = ? AND prompt IS NOT NULL AND prompt != ''
+                   ORDER BY created_at DESC
+                   LIMIT 20""",
+                (sid,),
+            ):
+                rows.append(dict(row))
+            if rows:
+                data["session_prompts"][sid] = rows  # newest first
+        except sqlite3.OperationalError:
+            pass
+
+    # Unhandled hook events and context compactions (timeline markers)
+    if sids:
+        marks = ",".join("?" * len(sids))
+        handled = ",".join("?" * len(_ccnotify.HANDLED_EVENTS))
+        try:
+            for row in conn.execute(
+                f"""SELECT session_id, event, payload, created_at FROM event
+                    WHERE session_id IN ({marks}) AND event NOT IN ({handled})
+                    ORDER BY created_at DESC
+                    LIMIT 200""",
+                tuple(sids) + tuple(_ccnotify.HANDLED_EVENTS),
+            ):
+                data["session_events"].setdefault(row["session_id"], []).append({
+                    "event": row["event"],
+                    "summary": event_summary(row["payload"]),
+                    "created_at": row["created_at"][:19],
+                })
+        except sqlite3.OperationalError:
+            pass
+        try:
+            for row in conn.execute(
+                f"""SELECT session_id, trigger, custom_instructions, created_at FROM compaction
+                    WHERE session_id IN ({marks})
+                    ORDER BY created_at DESC""",
+                tuple(sids),
+            ):
+                data["session_compactions"].setdefault(row["session_id"], []).append(dict(row))
+        except sqlite3.OperationalError:
+            pass
+
+    # Files each session touched, edited ones first
+    for sid in sids:
+        try:
+            rows = [dict(r) for r in conn.execute(
+                """SELECT path, SUM(op = 'read') as reads, SUM(op IN ('write', 'edit')) as edits,
+                          SUM(op = 'search') as searches, MAX(created_at) as last_at
+                   FROM file_touch WHERE session_id = ?
+                   GROUP BY path
+                   ORDER BY edits > 0 DESC, last_at DESC
+                   LIMIT 200""",
+                (sid,),
+            )]
+            if rows:
+                data["session_files"][sid] = rows
+        except sqlite3.OperationalError:
+            pass
+
+    # Diff stats per Edit/MultiEdit/Write call; the text itself is loaded when a diff is opened
+    for sid in sids:
+        try:
+            rows = [dict(r) for r in conn.execute(
+                """SELECT tool_use_id, tool_name, agent_id, path, added, removed, truncated, created_at
+                   FROM file_diff WHERE session_id = ?
+                   ORDER BY created_at DESC
+                   LIMIT 500""",
+                (sid,),
+            )]
+            if rows:
+                data["session_diffs"][sid] = rows
+        except sqlite3.OperationalError:
+            pass
+
+
+def _query_usage(conn, sids: set[str], data: dict) -> None:
+    """Token usage and cost per session (its agents included) and per agent."""
+    if sids:
+        try:
+            for row in conn.execute(
+                f"""SELECT session_id, agent_id, SUM({USAGE_TOKENS_SQL}) as tokens, SUM(cost_usd) as cost
+                    FROM usage WHERE session_id IN ({','.join('?' * len(sids))})
+                    GROUP BY session_id, agent_id""",
+                tuple(sids),
+            ):
+                su = data["session_usage"].setdefault(row["session_id"], {"tokens": 0, "cost": 0.0})
+                su["tokens"] += row["tokens"] or 0
+                su["cost"] += row["cost"] or 0.0
+                if row["agent_id"]:
+                    data["agent_usage"][row["agent_id"]] = {"tokens": row["tokens"] or 0, "cost": row["cost"] or 0.0}
+        except sqlite3.OperationalError:
+            pass
+
+
+def query_db(db_path: str, stats_range_idx: int = 2, stats_group_idx: int = 0) -> dict:
     data = {
         "active_sessions": [],
         "running_agents": [],
@@ -157,95 +316,71 @@ def query_db(db_path: str, stats_range_idx: int = 2) -> dict:
         "top_tools": [],
         "error_stats": [],
         "session_prompts": {},
+        "session_events": {},  # session_id -> [{event, summary, created_at}] for hooks ccnotify has no handler for
+        "session_compactions": {},  # session_id -> [{trigger, custom_instructions, created_at}]
+        "session_files": {},  # session_id -> [{path, reads, edits, searches, last_at}] for the FILES view
+        "top_files": [],  # most-edited files in the stats range
+        "session_conflicts": {},  # session_id -> paths it and another live session or agent both edited
+        "session_diffs": {},  # session_id -> [{tool_use_id, tool_name, path, added, removed, ...}], newest first
+        "stalled_sessions": {},  # session_id -> seconds without activity, per the watchdog
+        "stalled_agents": {},  # agent_id -> seconds without activity
+        "attention": [],  # open waits on the user, longest first: [{session_id, kind, message, started_at}]
+        "wait_totals": {},  # session_id -> seconds spent waiting on the user, open wait included
+        "state_daily": [],  # [{day, working, blocked}] seconds per day in the stats range, newest first
         "activity": {},
+        "session_usage": {},  # session_id -> {tokens, cost} including its agents
+        "agent_usage": {},  # agent_id -> {tokens, cost}
+        "spend_stats": [],
+        "notifications": [],  # newest first
     }
     if not os.path.exists(db_path):
         return data
 
     try:
-        conn = sqlite3.connect(db_path)
+        conn = connect_ro(db_path)
         conn.row_factory = sqlite3.Row
-        # Migrate: add columns if not present (existing installs)
-        cols = {r[1] for r in conn.execute("PRAGMA table_info(prompt)")}
-        if "pid" not in cols:
-            conn.execute("ALTER TABLE prompt ADD COLUMN pid INTEGER")
-        te_cols = {r[1] for r in conn.execute("PRAGMA table_info(tool_event)")}
-        for col, ctype in [("tool_input", "TEXT"), ("tool_response", "TEXT"), ("tool_use_id", "TEXT"), ("duration_ms", "INTEGER"), ("is_error", "INTEGER DEFAULT 0"), ("error_message", "TEXT"), ("cwd", "TEXT")]:
-            if col not in te_cols:
-                conn.execute(f"ALTER TABLE tool_event ADD COLUMN {col} {ctype}")
 
         # Active sessions: latest prompt per session, un-stopped only.
         # No time-based heuristics — we check the actual process PID below.
         for row in conn.execute(
-            """SELECT p.session_id, p.prompt, p.cwd, p.created_at, p.seq, p.lastWaitUserAt, p.pid
+            """SELECT p.session_id, p.prompt, p.cwd, p.created_at, p.seq, p.lastWaitUserAt, p.pid, p.location,
+                      p.git_root, p.git_branch, p.git_commit, p.git_dirty, p.model, p.permission_mode, p.source
                FROM prompt p
                INNER JOIN (
                    SELECT session_id, MAX(id) as max_id
                    FROM prompt
-                   WHERE stoped_at IS NULL
+                   WHERE stopped_at IS NULL
                    GROUP BY session_id
                ) latest ON p.id = latest.max_id
                ORDER BY p.created_at DESC
                LIMIT 50"""
         ):
-            data["active_sessions"].append(dict(row))
-
-        # Tombstone sessions whose Claude process is no longer alive.
-        # pid IS NULL means old row before this feature — fall back to 2h timeout for those.
-        dead_sids = []
-        for s in data["active_sessions"]:
-            pid = s.get("pid")
-            if pid:
-                try:
-                    os.kill(pid, 0)   # signal 0 = existence check, no side effects
-                except (ProcessLookupError, OSError):
-                    dead_sids.append(s["session_id"])
-        if dead_sids:
-            conn.execute(
-                f"UPDATE prompt SET stoped_at = datetime('now') WHERE session_id IN ({','.join('?'*len(dead_sids))}) AND stoped_at IS NULL",
-                dead_sids,
-            )
-            data["active_sessions"] = [s for s in data["active_sessions"] if s["session_id"] not in dead_sids]
-
-        # Fallback: tombstone old sessions with no pid recorded (pre-feature rows).
-        conn.execute(
-            """UPDATE prompt SET stoped_at = datetime('now')
-               WHERE stoped_at IS NULL
-                 AND pid IS NULL
-                 AND created_at < datetime('now', '-2 hours')
-                 AND (lastWaitUserAt IS NULL OR lastWaitUserAt < datetime('now', '-2 hours'))
-                 AND session_id NOT IN (
-                     SELECT DISTINCT session_id FROM tool_event
-                     WHERE created_at > datetime('now', '-30 minutes')
-                 )"""
-        )
-
-        # Reap orphaned agents: parent session has no open prompt rows (stoped_at IS NOT NULL
-        # on all its prompts), meaning Claude fired the Stop hook and the session is truly gone.
-        # This avoids reaping agents for sessions that are open but idle.
-        # Grace period of 5 min covers agents whose session just started.
-        conn.execute(
-            """UPDATE agent SET stopped_at = datetime('now')
-               WHERE stopped_at IS NULL
-                 AND started_at < datetime('now', '-5 minutes')
-                 AND session_id NOT IN (
-                     SELECT DISTINCT session_id FROM prompt WHERE stoped_at IS NULL
-                 )"""
-        )
-        conn.commit()
+            sess = dict(row)
+            sess["location"] = parse_location(sess.get("location"))
+            data["active_sessions"].append(sess)
+
+        # Hide sessions whose Claude process is gone; closing them out is left
+        # to maintenance (collector or `agent-top reap`) so the dashboard never writes.
+        # pid IS NULL means an old row from before pids were recorded.
+        dead_sids = {
+            s["session_id"] for s in data["active_sessions"]
+            if s.get("pid") and not _ccnotify.pid_alive(s["pid"])
+        }
+        data["active_sessions"] = [s for s in data["active_sessions"] if s["session_id"] not in dead_sids]
 
         # Running agents (skip ghosts with empty type)
         for row in conn.execute(
-            """SELECT agent_id, agent_type, session_id, cwd, started_at, transcript_path
+            """SELECT agent_id, agent_type, session_id, cwd, started_at, transcript_path, tool_use_id, parent_agent_id
                FROM agent
                WHERE stopped_at IS NULL
                ORDER BY started_at ASC"""
         ):
-            data["running_agents"].append(dict(row))
+            if row["session_id"] not in dead_sids:
+                data["running_agents"].append(dict(row))
 
         # Completed agents (skip ghosts)
         for row in conn.execute(
-            f"""SELECT agent_id, agent_type, session_id, cwd, started_at, stopped_at, transcript_path
+            f"""SELECT agent_id, agent_type, session_id, cwd, started_at, stopped_at, transcript_path, tool_use_id, parent_agent_id
                 FROM agent
                 WHERE stopped_at IS NOT NULL
                 ORDER BY stopped_at DESC
@@ -255,11 +390,11 @@ def query_db(db_path: str, stats_range_idx: int = 2) -> dict:
 
         # Recent completed prompts (skip system/task-notification noise)
         for row in conn.execute(
-            f"""SELECT session_id, prompt, cwd, created_at, stoped_at, seq
+            f"""SELECT session_id, prompt, cwd, created_at, stopped_at, seq
                 FROM prompt
-                WHERE stoped_at IS NOT NULL
+                WHERE stopped_at IS NOT NULL
                   AND prompt NOT LIKE '<%'
-                ORDER BY stoped_at DESC
+                ORDER BY stopped_at DESC
                 LIMIT {MAX_HISTORY}"""
         ):
             data["recent_prompts"].append(dict(row))
@@ -281,38 +416,82 @@ def query_db(db_path: str, stats_range_idx: int = 2) -> dict:
                 # Reverse so oldest is first (left-to-right reading)
                 data["tool_events"][sid] = list(reversed(tools))
 
-        # Session tools: extended tool list per active session (for DETAIL view)
-        for sid in active_sids:
+        # Timeline, markers, files and diffs per active session
+        _query_session_detail(conn, active_sids, data)
+
+        # Sessions blocked on the user, longest first, and how long each has waited in total
+        if active_sids:
+            marks = ",".join("?" * len(active_sids))
             try:
-                rows = []
+                data["attention"] = [dict(r) for r in conn.execute(
+                    f"""SELECT session_id, kind, message, started_at FROM wait
+                        WHERE ended_at IS NULL AND session_id IN ({marks})
+                        ORDER BY started_at""",
+                    tuple(active_sids),
+                )]
+                data["wait_totals"] = {sid: total for sid, total in conn.execute(
+                    f"""SELECT session_id,
+                               SUM(COALESCE(duration_ms / 1000.0, (julianday('now') - julianday(started_at)) * 86400))
+                        FROM wait WHERE session_id IN ({marks})
+                        GROUP BY session_id""",
+                    tuple(active_sids),
+                )}
+            except sqlite3.OperationalError:
+                pass
+
+        # Session state: the current one, and seconds spent in each (open stretch included)
+        if active_sids:
+            marks = ",".join("?" * len(active_sids))
+            by_sid = {sess["session_id"]: sess for sess in data["active_sessions"]}
+            try:
+                for row in conn.execute(
+                    f"""SELECT st.session_id, st.state, st.started_at FROM session_state st
+                        INNER JOIN (
+                            SELECT MAX(id) as max_id FROM session_state
+                            WHERE session_id IN ({marks}) GROUP BY session_id
+                        ) latest ON st.id = latest.max_id""",
+                    tuple(active_sids),
+                ):
+                    by_sid[row["session_id"]].update(state=row["state"], state_since=row["started_at"])
                 for row in conn.execute(
-                    """SELECT tool_name, tool_label, created_at, tool_input, tool_response, duration_ms, is_error, error_message, cwd FROM tool_event
-                       WHERE session_id = ?
-                       ORDER BY created_at DESC
-                       LIMIT 200""",
-                    (sid,),
+                    f"""SELECT session_id, state,
+                               SUM(COALESCE(duration_ms / 1000.0, (julianday('now') - julianday(started_at)) * 86400)) as secs
+                        FROM session_state WHERE session_id IN ({marks})
+                        GROUP BY session_id, state""",
+                    tuple(active_sids),
                 ):
-                    rows.append(dict(row))
-                if rows:
-                    data["session_tools"][sid] = list(reversed(rows))
+                    by_sid[row["session_id"]].setdefault("state_totals", {})[row["state"]] = row["secs"]
             except sqlite3.OperationalError:
                 pass
 
-        # Session prompts: all prompts for active sessions (for interleaved tree view)
-        data["session_prompts"] = {}
-        for sid in active_sids:
+        # Watchdog: working sessions and running agents that have gone quiet
+        try:
+            for st in _ccnotify.find_stalls(conn, float(_ccnotify.watchdog_config().get("stall_after") or 0)):
+                if st["kind"] == "agent":
+                    data["stalled_agents"][st["agent_id"]] = st["idle"]
+                elif st["session_id"] in active_sids:
+                    data["stalled_sessions"][st["session_id"]] = st["idle"]
+        except sqlite3.OperationalError:
+            pass
+
+        # Edit conflicts still in their window, between parties that are both live
+        window = float(_ccnotify.conflict_config().get("window") or 0)
+        if active_sids and window > 0:
+            marks = ",".join("?" * len(active_sids))
+            live_agent = "(c.{0} = '' OR EXISTS (SELECT 1 FROM agent a WHERE a.agent_id = c.{0} AND a.stopped_at IS NULL))"
             try:
-                rows = []
                 for row in conn.execute(
-                    """SELECT prompt, created_at FROM prompt
-                       WHERE session_id = ? AND prompt IS NOT NULL AND prompt != ''
-                       ORDER BY created_at DESC
-                       LIMIT 20""",
-                    (sid,),
+                    f"""SELECT c.path, c.session_a, c.session_b FROM conflict c
+                        WHERE c.last_seen_at >= datetime('now', ?)
+                          AND c.session_a IN ({marks}) AND c.session_b IN ({marks})
+                          AND {live_agent.format("agent_a")} AND {live_agent.format("agent_b")}
+                        ORDER BY c.last_seen_at DESC""",
+                    (f"-{window} seconds", *active_sids, *active_sids),
                 ):
-                    rows.append(dict(row))
-                if rows:
-                    data["session_prompts"][sid] = rows  # newest first
+                    for sid in {row["session_a"], row["session_b"]}:
+                        paths = data["session_conflicts"].setdefault(sid, [])
+                        if row["path"] not in paths:
+                            paths.append(row["path"])
             except sqlite3.OperationalError:
                 pass
 
@@ -337,20 +516,44 @@ def query_db(db_path: str, stats_range_idx: int = 2) -> dict:
             except Exception:
                 pass
 
-        # Usage stats: top agent types + top tools
+        # Token usage for the sessions and agents on screen
+        _query_usage(conn, set(active_sids) | {a["session_id"] for a in data["running_agents"] + data["completed_agents"]}, data)
+
+        # Usage stats: top agent types + top tools. Live rows plus the daily
+        # aggregates that retention rolls pruned rows into; the aggregates are
+        # per day, so they're left out of the 1h range.
         _, sql_interval = STATS_RANGES[stats_range_idx]
         if sql_interval:
             agent_where = f"WHERE started_at > datetime('now', '{sql_interval}') AND agent_type != ''"
             tool_where = f"WHERE te.created_at > datetime('now', '{sql_interval}')"
+            daily_where = f"WHERE day >= date('now', '{sql_interval}')" if "day" in sql_interval else "WHERE 0"
         else:
             agent_where = "WHERE agent_type != ''"
             tool_where = ""
+            daily_where = ""
+        # Project of the session a tool ran in (agents' tool events carry a worktree cwd)
+        tool_cwd = f"COALESCE(({SESSION_CWD_SQL}), te.cwd)"
+        agent_cwd = "cwd"
+        usage_cwd = SESSION_CWD_SQL.replace("te.", "u.")
+        # Grouped by another session attribute: same column, filled from the session's
+        # prompt rows. The daily aggregates only know the project, so they drop out.
+        group_col = STATS_GROUPS[stats_group_idx][1]
+        data["_stats_group_idx"] = stats_group_idx
+        if group_col:
+            tool_cwd = f"({session_attr_sql(group_col, 'te.session_id')})"
+            agent_cwd = f"({session_attr_sql(group_col, 'agent.session_id')})"
+            usage_cwd = session_attr_sql(group_col, "u.session_id")
+            daily_where = "WHERE 0"
         try:
             data["top_agents"] = [
                 dict(r) for r in conn.execute(
-                    f"""SELECT agent_type, cwd, COUNT(*) as cnt FROM agent
-                       {agent_where}
-                       GROUP BY agent_type, cwd ORDER BY cnt DESC LIMIT 12"""
+                    f"""SELECT agent_type, cwd, SUM(cnt) as cnt FROM (
+                           SELECT agent_type, {agent_cwd} as cwd, COUNT(*) as cnt FROM agent
+                           {agent_where}
+                           GROUP BY 1, 2
+                           UNION ALL
+                           SELECT agent_type, NULLIF(cwd, ''), runs FROM agent_daily {daily_where}
+                       ) GROUP BY agent_type, cwd ORDER BY cnt DESC LIMIT 12"""
                 )
             ]
         except sqlite3.OperationalError:
@@ -358,26 +561,84 @@ def query_db(db_path: str, stats_range_idx: int = 2) -> dict:
         try:
             data["top_tools"] = [
                 dict(r) for r in conn.execute(
-                    f"""SELECT te.tool_name, p.cwd, COUNT(*) as cnt
-                       FROM tool_event te
-                       LEFT JOIN prompt p ON te.session_id = p.session_id
-                       {tool_where}
-                       GROUP BY te.tool_name, p.cwd ORDER BY cnt DESC LIMIT 12"""
+                    f"""SELECT tool_name, cwd, SUM(cnt) as cnt FROM (
+                           SELECT te.tool_name, {tool_cwd} as cwd, COUNT(*) as cnt
+                           FROM tool_event te
+                           {tool_where}
+                           GROUP BY 1, 2
+                           UNION ALL
+                           SELECT tool_name, NULLIF(cwd, ''), calls FROM tool_daily {daily_where}
+                       ) GROUP BY tool_name, cwd ORDER BY cnt DESC LIMIT 12"""
                 )
             ]
         except sqlite3.OperationalError:
             data["top_tools"] = []
 
+        # Most-edited files
+        try:
+            file_where = f"AND created_at > datetime('now', '{sql_interval}')" if sql_interval else ""
+            data["top_files"] = [
+                dict(r) for r in conn.execute(
+                    f"""SELECT path, COUNT(*) as cnt, COUNT(DISTINCT session_id) as sessions
+                        FROM file_touch
+                        WHERE op IN ('write', 'edit') {file_where}
+                        GROUP BY path ORDER BY cnt DESC LIMIT 8"""
+                )
+            ]
+        except sqlite3.OperationalError:
+            data["top_files"] = []
+
+        # Spend by project + model
+        try:
+            spend_where = f"WHERE u.created_at > datetime('now', '{sql_interval}')" if sql_interval else ""
+            data["spend_stats"] = [
+                dict(r) for r in conn.execute(
+                    f"""SELECT ({usage_cwd}) as cwd,
+                               u.model, SUM({USAGE_TOKENS_SQL}) as tokens, SUM(u.cost_usd) as cost
+                        FROM usage u
+                        {spend_where}
+                        GROUP BY 1, 2
+                        ORDER BY cost DESC, tokens DESC"""
+                )
+            ]
+        except sqlite3.OperationalError:
+            data["spend_stats"] = []
+
+        # Time working vs blocked on the user, per day
+        try:
+            state_where = f"WHERE started_at > datetime('now', '{sql_interval}')" if sql_interval else ""
+            blocked = ", ".join(f"'{st}'" for st in _ccnotify.BLOCKED_STATES)
+            data["state_daily"] = [
+                dict(r) for r in conn.execute(
+                    f"""SELECT day,
+                               SUM(CASE WHEN state = 'working' THEN secs ELSE 0 END) as working,
+                               SUM(CASE WHEN state IN ({blocked}) THEN secs ELSE 0 END) as blocked
+                        FROM (
+                            SELECT date(started_at) as day, state,
+                                   COALESCE(duration_ms / 1000.0, (julianday('now') - julianday(started_at)) * 86400) as secs
+                            FROM session_state {state_where}
+                        )
+                        GROUP BY day ORDER BY day DESC LIMIT 7"""
+                )
+            ]
+        except sqlite3.OperationalError:
+            data["state_daily"] = []
+
         # Error stats: tools with is_error=1 grouped by tool+cwd
         try:
             error_time_filter = f"AND te.created_at > datetime('now', '{sql_interval}')" if sql_interval else ""
+            daily_errors = f"{daily_where} {'AND' if daily_where else 'WHERE'} errors > 0"
             data["error_stats"] = [
                 dict(r) for r in conn.execute(
-                    f"""SELECT te.tool_name, p.cwd, COUNT(*) as cnt
-                        FROM tool_event te
-                        LEFT JOIN prompt p ON te.session_id = p.session_id
-                        WHERE te.is_error = 1 {error_time_filter}
-                        GROUP BY te.tool_name, p.cwd
+                    f"""SELECT tool_name, cwd, SUM(cnt) as cnt FROM (
+                            SELECT te.tool_name, {tool_cwd} as cwd, COUNT(*) as cnt
+                            FROM tool_event te
+                            WHERE te.is_error = 1 {error_time_filter}
+                            GROUP BY 1, 2
+                            UNION ALL
+                            SELECT tool_name, NULLIF(cwd, ''), errors FROM tool_daily {daily_errors}
+                        )
+                        GROUP BY tool_name, cwd
                         ORDER BY cnt DESC
                         LIMIT 8"""
                 )
@@ -385,6 +646,17 @@ def query_db(db_path: str, stats_range_idx: int = 2) -> dict:
         except sqlite3.OperationalError:
             data["error_stats"] = []
 
+        try:
+            data["notifications"] = [
+                dict(r) for r in conn.execute(
+                    """SELECT id, session_id, cwd, event, title, subtitle, kind, status, reason, created_at
+                       FROM notification ORDER BY id DESC LIMIT ?""",
+                    (MAX_NOTIFICATIONS,),
+                )
+            ]
+        except sqlite3.OperationalError:
+            data["notifications"] = []
+
         conn.close()
     except sqlite3.OperationalError:
         pass
@@ -392,6 +664,106 @@ def query_db(db_path: str, stats_range_idx: int = 2) -> dict:
     return data
 
 
+# HISTORY browser filters (`t`, `u`, `n`; `p` cycles the projects in the list): label, minimum
+HISTORY_DURATIONS = [("any length", 0), ("\u22651m", 60), ("\u226510m", 600), ("\u22651h", 3600)]
+HISTORY_AGENTS = [("any agents", 0), ("\u22651 agent", 1), ("\u22653 agents", 3)]
+MAX_HISTORY_SESSIONS = 500
+
+# Per-session dicts query_db fills for active sessions and load_session for a past one
+SESSION_DETAIL_KEYS = ("session_tools", "session_prompts", "session_events", "session_compactions",
+                       "session_files", "session_diffs", "session_usage", "agent_usage")
+
+
+def query_history(db_path: str, range_idx: int = 2, min_secs: int = 0, min_agents: int = 0) -> list[dict]:
+    """Ended sessions (every prompt row stopped) that started in the range, most recently ended first."""
+    if not os.path.exists(db_path):
+        return []
+    _, sql_interval = STATS_RANGES[range_idx]
+    context = ", ".join(f"({session_attr_sql(k, 's.session_id')}) as {k}"
+                        for k in ("git_root", "git_branch", "git_commit", "model", "permission_mode"))
+    try:
+        conn = connect_ro(db_path)
+        conn.row_factory = sqlite3.Row
+        rows = [dict(r) for r in conn.execute(
+            f"""SELECT s.*,
+                       (SELECT p.cwd FROM prompt p WHERE p.session_id = s.session_id ORDER BY p.id LIMIT 1) as cwd,
+                       (SELECT p.prompt FROM prompt p WHERE p.session_id = s.session_id
+                          AND p.prompt != '' AND p.prompt NOT LIKE '<%' ORDER BY p.id LIMIT 1) as prompt,
+                       (SELECT COUNT(*) FROM agent a WHERE a.session_id = s.session_id AND a.agent_type != '') as agents,
+                       (SELECT COUNT(*) FROM tool_event te WHERE te.session_id = s.session_id) as tools,
+                       {context}
+                FROM (
+                    SELECT session_id, MIN(created_at) as started_at, MAX(stopped_at) as stopped_at, COUNT(*) as prompts,
+                           (julianday(MAX(stopped_at)) - julianday(MIN(created_at))) * 86400 as secs
+                    FROM prompt
+                    GROUP BY session_id
+                    HAVING SUM(stopped_at IS NULL) = 0
+                       {f"AND MIN(created_at) > datetime('now', '{sql_interval}')" if sql_interval else ""}
+                       AND secs >= ?
+                ) s
+                WHERE agents >= ?
+                ORDER BY s.stopped_at DESC
+                LIMIT {MAX_HISTORY_SESSIONS}""",
+            (min_secs, min_agents),
+        )]
+        conn.close()
+        return rows
+    except sqlite3.OperationalError:
+        return []
+
+
+def load_session(db_path: str, session_id: str) -> dict:
+    """Everything the TREE, TIMELINE and DETAIL views need for one session, live or not, read from the database."""
+    data = {key: {} for key in SESSION_DETAIL_KEYS}
+    data.update(session_id=session_id, agents=[])
+    try:
+        conn = connect_ro(db_path)
+        conn.row_factory = sqlite3.Row
+        _query_session_detail(conn, [session_id], data)
+        _query_usage(conn, {session_id}, data)
+        data["agents"] = [dict(r) for r in conn.execute(
+            """SELECT agent_id, agent_type, session_id, cwd, started_at, stopped_at, transcript_path, tool_use_id, parent_agent_id
+               FROM agent
+               WHERE session_id = ? AND stopped_at IS NOT NULL
+               ORDER BY stopped_at DESC""",
+            (session_id,),
+        )]
+        conn.close()
+    except sqlite3.OperationalError:
+        pass
+    return data
+
+
+def load_read_marker(db_path: str | None = None) -> int:
+    """Id of the newest notification already seen for this database."""
+    try:
+        with open(READ_STATE_PATH, "r", encoding="utf-8") as f:
+            return int(json.load(f).get("notifications_read", {}).get(os.path.abspath(db_path or DB_PATH), 0))
+    except (OSError, ValueError, AttributeError, TypeError):
+        return 0
+
+
+def save_read_marker(last_id: int, db_path: str | None = None) -> None:
+    try:
+        with open(READ_STATE_PATH, "r", encoding="utf-8") as f:
+            st = json.load(f)
+    except (OSError, ValueError):
+        st = {}
+    if not isinstance(st, dict):
+        st = {}
+    st.setdefault("notifications_read", {})[os.path.abspath(db_path or DB_PATH)] = last_id
+    try:
+        os.makedirs(os.path.dirname(READ_STATE_PATH), exist_ok=True)
+        with open(READ_STATE_PATH, "w", encoding="utf-8") as f:
+            json.dump(st, f)
+    except OSError:
+        pass  # read state just won't survive a restart
+
+
+def unread_count(notifications: list[dict], read_marker: int) -> int:
+    return sum(1 for n in notifications if n["id"] > read_marker and n.get("status") == "delivered")
+
+
 def read_team_tasks(team_name: str) -> list[dict]:
     """Read task JSON files from ~/.claude/tasks/{team_name}/."""
     tasks_dir = os.path.expanduser(f"~/.claude/tasks/{team_name}")
@@ -448,7 +820,7 @@ def query_teams(db_path: str, active_session_ids: set[str]) -> dict:
     # 2. Supplement with team_session DB table (catches teammates not yet in config files)
     if os.path.exists(db_path):
         try:
-            conn = sqlite3.connect(db_path)
+            conn = connect_ro(db_path)
             conn.row_factory = sqlite3.Row
             for row in conn.execute("SELECT session_id, team_name, teammate_name FROM team_session"):
                 sid, tname, mname = row["session_id"], row["team_name"], row["teammate_name"]
@@ -479,6 +851,97 @@ def query_teams(db_path: str, active_session_ids: set[str]) -> dict:
 
 # ── FORMATTERS ───────────────────────────────────────────────
 
+def parse_location(raw: str | None) -> dict:
+    """Decode the pane location JSON ccnotify stores on prompt rows."""
+    if not raw:
+        return {}
+    try:
+        loc = json.loads(raw)
+        return loc if isinstance(loc, dict) else {}
+    except Exception:
+        return {}
+
+
+def context_line(sess: dict) -> str:
+    """Session context for detail headers, e.g. '⎇ main@1a2b3c4* · sonnet-4-5 · plan · resume'."""
+    parts = []
+    if sess.get("git_root"):
+        ref = sess.get("git_branch") or "(detached)"
+        if sess.get("git_commit"):
+            ref += f"@{sess['git_commit'][:7]}"
+        if sess.get("git_dirty"):
+            ref += "*"
+        repo = os.path.basename(sess["git_root"])
+        cwd = sess.get("cwd") or ""
+        # Only name the repo when the session isn't running at its root
+        parts.append(f"\u2387 {ref}" + (f" ({repo})" if cwd.rstrip("/") != sess["git_root"].rstrip("/") else ""))
+    if sess.get("model"):
+        parts.append(short_model(sess["model"]))
+    if sess.get("permission_mode") and sess["permission_mode"] != "default":
+        parts.append(sess["permission_mode"])
+    if sess.get("source") and sess["source"] != "startup":
+        parts.append(sess["source"])
+    return " \u00b7 ".join(parts)
+
+
+def session_waiting(sess: dict) -> bool:
+    """Whether a session is parked on the user: by its state, or lastWaitUserAt for hooks that predate it."""
+    if sess.get("state"):
+        return sess["state"] in _ccnotify.BLOCKED_STATES or sess["state"] == "idle"
+    return bool(sess.get("lastWaitUserAt"))
+
+
+def state_label(state: str | None) -> tuple[str, int]:
+    """Short label and colour for the SESSIONS state column."""
+    return {
+        "starting": ("start", CYAN),
+        "working": ("work", GREEN),
+        "waiting_input": ("input", YELLOW),
+        "waiting_permission": ("perm", RED),
+        "idle": ("idle", DIM),
+        "ended": ("ended", DIM),
+        "crashed": ("crash", RED),
+    }.get(state or "", ("", DIM))
+
+
+def state_line(sess: dict) -> str:
+    """Time-in-state for detail headers, e.g. 'waiting for input for 2m10s · working 14m · blocked on you 3m05s'."""
+    if not sess.get("state"):
+        return ""
+    totals = sess.get("state_totals") or {}
+    name = sess["state"].replace("_", " for ")  # waiting_input -> "waiting for input"
+    parts = [f"{name} for {fmt_dur(sess['state_since'])}" if sess.get("state_since") else name]
+    parts.append(f"working {fmt_dur_seconds(totals.get('working') or 0)}")
+    blocked = sum(totals.get(st) or 0 for st in _ccnotify.BLOCKED_STATES)
+    parts.append(f"blocked on you {fmt_dur_seconds(blocked)}")
+    return " \u00b7 ".join(parts)
+
+
+def stat_tag(entry: dict, group_idx: int) -> str:
+    """Row tag for a STATS entry: [project], or the grouping value ([main], [sonnet-4-5], ...)."""
+    value = entry.get("cwd")
+    col = STATS_GROUPS[group_idx][1]
+    if not col:
+        return dir_tag(value or "")
+    if col == "model" and value:
+        value = short_model(value)
+    return f"[{value or '-'}]"
+
+
+def stat_scope(entry: dict, group_idx: int) -> dict:
+    """What a STATS row's drill-down filters on: its project, or its grouping column and value."""
+    col = STATS_GROUPS[group_idx][1]
+    if not col:
+        return {"cwd": entry.get("cwd") or ""}
+    return {"cwd": "", "group_col": col, "group_val": entry.get("cwd")}
+
+
+def location_line(loc: dict) -> str:
+    """One-line pane location for detail headers, e.g. '⌖ tmux work:2.1 · editor'."""
+    label = (loc or {}).get("label", "")
+    return f"\u2316 {label}" if label else ""
+
+
 def dir_tag(cwd: str) -> str:
     """Return a short [dirname] prefix from a cwd path."""
     if not cwd:
@@ -581,6 +1044,70 @@ end tell"""
 
 
 
+def _run_focus(cmds: list[list[str]]) -> str:
+    """Run focus commands in order; return the first failure message or ''."""
+    for cmd in cmds:
+        try:
+            r = subprocess.run(cmd, capture_output=True, text=True, timeout=3)
+        except FileNotFoundError:
+            return f"{cmd[0]} not found"
+        except subprocess.TimeoutExpired:
+            return f"{cmd[0]}: timed out"
+        if r.returncode != 0:
+            return f"{cmd[0]}: {(r.stderr or r.stdout).strip()[:60]}"
+    return ""
+
+
+def focus_location(loc: dict) -> str:
+    """Bring the terminal pane described by a ccnotify location to the front.
+
+    Returns an error message, or '' on success.
+    """
+    term = loc.get("terminal", "")
+    if term == "tmux":
+        base = ["tmux"] + (["-S", loc["socket"]] if loc.get("socket") else [])
+        pane = loc.get("pane_id", "")
+        cmds = [base + ["select-window", "-t", pane], base + ["select-pane", "-t", pane]]
+        # switch-client needs an attached client — only possible when we run inside tmux too
+        if os.environ.get("TMUX"):
+            cmds.insert(0, base + ["switch-client", "-t", pane])
+        return _run_focus(cmds)
+    if term == "kitty":
+        to = ["--to", loc["listen_on"]] if loc.get("listen_on") else []
+        return _run_focus([["kitty", "@"] + to + ["focus-window", "--match", f"id:{loc.get('window_id', '')}"]])
+    if term == "wezterm":
+        return _run_focus([["wezterm", "cli", "activate-pane", "--pane-id", str(loc.get("pane_id", ""))]])
+    if term == "iterm2":
+        script = f"""tell application "iTerm2"
+  repeat with w in windows
+    repeat with t in tabs of w
+      repeat with s in sessions of t
+        if unique ID of s is "{loc.get('session_id', '')}" then
+          select w
+          tell t to select
+          tell s to select
+          activate
+          return
+        end if
+      end repeat
+    end repeat
+  end repeat
+end tell"""
+        return _run_focus([["osascript", "-e", script]])
+    if term == "zellij":
+        return f"zellij can't focus by id — session {loc.get('session', '?')}, pane {loc.get('pane_id', '?')}"
+    return "no pane location recorded"
+
+
+def jump_to_item(item: dict, cache: dict) -> str:
+    """Focus the pane of a selected session, teammate or agent. Returns a status message."""
+    loc = item.get("location") or cache.get("session_lookup", {}).get(item.get("session_id", ""), {}).get("location")
+    if not loc:
+        return "no pane location recorded"
+    err = focus_location(loc)
+    return err or f"\u2192 {loc.get('label') or loc.get('terminal', 'pane')}"
+
+
 def parse_dt(ts: str | None) -> datetime | None:
     """Parse ISO timestamp string to timezone-aware datetime."""
     if not ts:
@@ -631,6 +1158,32 @@ def fmt_time(ts: str | None) -> str:
         return "?"
 
 
+def fmt_tokens(n: int) -> str:
+    if n >= 1_000_000:
+        return f"{n / 1_000_000:.1f}M"
+    if n >= 1_000:
+        return f"{n / 1_000:.1f}k"
+    return str(n)
+
+
+def fmt_cost(usd: float) -> str:
+    return f"${usd:.2f}" if usd >= 0.01 or usd == 0 else "<$0.01"
+
+
+def usage_label(u: dict | None) -> str:
+    """'12.3k tok · $0.42' for a session/agent usage entry, or '' without data."""
+    if not u or not u.get("tokens"):
+        return ""
+    return f"{fmt_tokens(u['tokens'])} tok \u00b7 {fmt_cost(u['cost'])}"
+
+
+def short_model(model: str | None) -> str:
+    """claude-sonnet-4-5-20250929 -> sonnet-4-5"""
+    if not model:
+        return "?"
+    return re.sub(r"-\d{8}$", "", model.removeprefix("claude-"))
+
+
 def short_id(s: str) -> str:
     return s[:7] if len(s) > 8 else s
 
@@ -648,6 +1201,27 @@ def short_prompt(p: str | None, maxlen: int = 40) -> str:
     return p[:maxlen] + ".." if len(p) > maxlen else p
 
 
+# Payload keys every hook carries — left out of a generic event's summary
+EVENT_COMMON_KEYS = {"session_id", "transcript_path", "cwd", "hook_event_name", "permission_mode"}
+
+
+def event_summary(payload: str | None, maxlen: int = 80) -> str:
+    """'key=value key=value' from an unhandled hook payload's scalar fields."""
+    try:
+        obj = json.loads(payload or "{}")
+    except ValueError:
+        return ""
+    if not isinstance(obj, dict):
+        return ""
+    parts = []
+    for k, v in obj.items():
+        if k in EVENT_COMMON_KEYS or isinstance(v, (dict, list)) or v in (None, ""):
+            continue
+        parts.append(f"{k}={str(v).replace(chr(10), ' ')[:30]}")
+    text = " ".join(parts)
+    return text[:maxlen] + ".." if len(text) > maxlen else text
+
+
 def safe_add(stdscr, row: int, col: int, text: str, width: int, attr=0):
     try:
         stdscr.addnstr(row, col, text, max(0, width - col), attr)
@@ -715,11 +1289,19 @@ def life_render(grid, rows, cols, char_w, char_h):
 # ── VISUALIZATION MODES ──────────────────────────────────────
 
 
+def _flatten_agent_track(track: dict, depth: int, out: list) -> None:
+    """Append a gantt track, then its child agents (by start time) one level deeper."""
+    track["_depth"] = depth
+    out.append(track)
+    for child in sorted(track.get("_subtree", []), key=lambda t: t["start"]):
+        _flatten_agent_track(child, depth + 1, out)
+
+
 def _draw_viz_gantt(stdscr, y, x, h, w, cache, state):
     """Gantt chart: one bar per agent, x-axis = active window of the session."""
     active_all = cache.get("active_all", [])
     r_agents = cache.get("r_agents", [])
-    c_agents = cache.get("c_agents", [])
+    c_agents = cache.get("c_agents", []) + cache.get("loaded_agents", [])
     rw = x + w - 1
 
     # Scope to selected session
@@ -736,9 +1318,11 @@ def _draw_viz_gantt(stdscr, y, x, h, w, cache, state):
     # Collect agents for this session
     now_utc = datetime.now(timezone.utc)
     running_ids = {a["agent_id"] for a in r_agents}
+    stalled_agents = cache.get("data", {}).get("stalled_agents", {})
     all_agents = [a for a in (r_agents + c_agents)
                   if a.get("session_id") == target_sid and a.get("agent_type")]
     tracks = []
+    agent_tracks = {}
     for a in sorted(all_agents, key=lambda a: a.get("started_at", "")):
         a_start = parse_dt(a.get("started_at"))
         if not a_start:
@@ -748,12 +1332,22 @@ def _draw_viz_gantt(stdscr, y, x, h, w, cache, state):
         running = a["agent_id"] in running_ids
         if not running and (a_end - a_start).total_seconds() < 1:
             continue
-        tracks.append({
+        agent_tracks[a["agent_id"]] = {
             "label": a["agent_type"],
             "start": a_start,
             "end": a_end,
             "running": running,
-        })
+            "stalled": stalled_agents.get(a["agent_id"]) if running else None,
+            "_parent_id": a.get("parent_agent_id"),
+            "_subtree": [],
+        }
+    # Child agents ride along with their parent's bar and are laid out beneath it
+    for aid, track in agent_tracks.items():
+        parent = agent_tracks.get(track["_parent_id"])
+        if parent is not None and parent is not track:
+            parent["_subtree"].append(track)
+        else:
+            tracks.append(track)
 
     # Always add tool burst bars — shows session activity alongside agents
     session_tools = cache.get("session_tools", {})
@@ -803,10 +1397,17 @@ def _draw_viz_gantt(stdscr, y, x, h, w, cache, state):
         p_ts = parse_dt(p.get("created_at"))
         if p_ts:
             prompt_rows.append({"start": p_ts, "_is_prompt": True, "text": p_text})
+    # Context compactions get a marker row at the point they happened
+    for c in cache.get("session_compactions", {}).get(target_sid, []):
+        c_ts = parse_dt(c.get("created_at"))
+        if c_ts:
+            prompt_rows.append({"start": c_ts, "_is_compact": True, "trigger": c.get("trigger") or "?"})
     # Merge prompts into tracks
     merged = tracks + prompt_rows
     merged.sort(key=lambda t: t["start"])
-    tracks = merged
+    tracks = []
+    for track in merged:
+        _flatten_agent_track(track, 0, tracks)
 
     # Active window: earliest start → latest end (bar tracks only, not prompt separators)
     bar_tracks = [t for t in tracks if "end" in t]
@@ -831,7 +1432,11 @@ def _draw_viz_gantt(stdscr, y, x, h, w, cache, state):
     pr = y
 
     # Header
-    safe_add(stdscr, pr, x + 2, f"active {fmt_dur_seconds(active_s)}  ({len(tracks)} agents)", rw, DIM)
+    header = f"active {fmt_dur_seconds(active_s)}  ({len(tracks)} agents)"
+    safe_add(stdscr, pr, x + 2, header, rw, DIM)
+    stalled = cache.get("data", {}).get("stalled_sessions", {}).get(target_sid)
+    if stalled is not None:
+        safe_add(stdscr, pr, x + 4 + len(header), f"\u29d7 no activity for {fmt_dur_seconds(stalled)}", rw, RED)
     pr += 1
 
     # Agent bars
@@ -852,8 +1457,21 @@ def _draw_viz_gantt(stdscr, y, x, h, w, cache, state):
             pr += 1
             continue
 
+        # Compaction marker: a tick on the bar axis where context was compacted
+        if track.get("_is_compact"):
+            col = int((track["start"] - window_start).total_seconds() / span * bar_w)
+            col = max(0, min(bar_w - 1, col))
+            safe_add(stdscr, pr, x + 2, f"\u27f3 compact".ljust(label_w), rw, CYAN)
+            safe_add(stdscr, pr, bar_x, "\u2500" * col + "\u253c" + "\u2500" * (bar_w - col - 1), rw, DIM)
+            safe_add(stdscr, pr, bar_x + col, "\u253c", rw, CYAN)
+            safe_add(stdscr, pr, bar_x + bar_w + 1, track["trigger"], rw, DIM)
+            pr += 1
+            continue
+
         if track.get("_is_burst"):
             color = YELLOW
+        elif track.get("stalled") is not None:
+            color = RED
         elif track["running"]:
             color = MAGENTA
         else:
@@ -871,12 +1489,17 @@ def _draw_viz_gantt(stdscr, y, x, h, w, cache, state):
         bar += "\u2591" * max(0, bar_w - len(bar))
 
         dur = fmt_dur(track["start"].isoformat(), track["end"].isoformat() if not track["running"] else None)
-        if track["running"]:
+        if track.get("stalled") is not None:
+            idle = track["stalled"] or 0
+            dur = f"\u29d7 idle {fmt_dur_seconds(idle) if idle < 60 else f'{int(idle // 60)}m'}"
+        elif track["running"]:
             dur = "\u25c6 " + dur
 
-        safe_add(stdscr, pr, x + 2, track["label"][:label_w].ljust(label_w), rw, color)
+        depth = min(track.get("_depth", 0), 4)
+        label = ("  " * (depth - 1) + "\u2514 " if depth else "") + track["label"]
+        safe_add(stdscr, pr, x + 2, label[:label_w].ljust(label_w), rw, color)
         safe_add(stdscr, pr, bar_x, bar[:bar_w], rw, color)
-        safe_add(stdscr, pr, bar_x + bar_w + 1, dur, rw, DIM)
+        safe_add(stdscr, pr, bar_x + bar_w + 1, dur, rw, RED if track.get("stalled") is not None else DIM)
         pr += 1
 
     # Scroll indicator
@@ -889,6 +1512,279 @@ def _draw_viz_gantt(stdscr, y, x, h, w, cache, state):
     safe_add(stdscr, axis_row, bar_x, axis[:bar_w], rw, DIM)
 
 
+def _draw_viz_notifications(stdscr, y, x, h, w, cache, state):
+    """Notification history, newest first. Rows that were unread when the view opened keep a dot."""
+    notifs = cache.get("data", {}).get("notifications", [])
+    rw = x + w - 1
+    state["_tree_len"] = len(notifs)
+    state["_notif_list"] = notifs
+    if not notifs:
+        safe_add(stdscr, y, x + 2, "(no notifications)", rw, DIM)
+        return
+
+    # Opening the view marks everything read; remember the old marker for the dots
+    if state.get("_notif_seen") is None:
+        state["_notif_seen"] = state.get("notif_read", 0)
+    if notifs[0]["id"] > state.get("notif_read", 0):
+        state["notif_read"] = notifs[0]["id"]
+        save_read_marker(notifs[0]["id"])
+    seen = state["_notif_seen"]
+
+    cursor = min(state.get("tree_cursor", 0), len(notifs) - 1)
+    state["tree_cursor"] = cursor
+    scroll = state.get("detail_scroll", 0)
+    if cursor < scroll:
+        scroll = cursor
+    elif cursor >= scroll + h:
+        scroll = cursor - h + 1
+    state["detail_scroll"] = scroll
+    focused = state.get("focus") == "right"
+    kind_colors = {"permission": YELLOW, "waiting_input": YELLOW, "error": RED}
+
+    pr = y
+    for idx in range(scroll, min(len(notifs), scroll + h)):
+        n = notifs[idx]
+        status = n.get("status") or ""
+        unread = status == "delivered" and n["id"] > seen
+        if status == "delivered":
+            color = kind_colors.get(n.get("kind"), GREEN) if unread else WHITE
+            note = n.get("reason") or ""
+        elif status == "held":
+            color = CYAN
+            note = f"held: {n.get('reason')}" if n.get("reason") else "held"
+        else:
+            color = DIM
+            note = f"{status}: {n.get('reason')}" if n.get("reason") else status
+        text = " \u00b7 ".join(t for t in (n.get("title"), n.get("subtitle")) if t)
+        note_w = min(len(note), max(0, w - 30))
+        text_w = max(0, w - 16 - note_w - (1 if note_w else 0))
+        rev = curses.A_REVERSE if focused and idx == cursor else 0
+        if rev:
+            safe_add(stdscr, pr, x + 1, " " * (w - 2), rw, rev)
+        safe_add(stdscr, pr, x + 2, fmt_time(n.get("created_at")), rw, DIM | rev)
+        safe_add(stdscr, pr, x + 11, "\u25cf" if unread else " ", rw, CYAN | rev)
+        safe_add(stdscr, pr, x + 13, text[:text_w], rw, color | rev)
+        if note_w:
+            safe_add(stdscr, pr, rw - note_w - 1, note[:note_w], rw, DIM | rev)
+        pr += 1
+
+
+def short_path(path: str, base: str = "", maxlen: int = 60) -> str:
+    """Path relative to base when it lies under it, trimmed from the left to maxlen."""
+    if base and (path == base or path.startswith(base.rstrip("/") + "/")):
+        path = os.path.relpath(path, base)
+    elif path.startswith(os.path.expanduser("~") + "/"):
+        path = "~" + path[len(os.path.expanduser("~")):]
+    return path if len(path) <= maxlen else "\u2026" + path[-(maxlen - 1):]
+
+
+def _draw_viz_files(stdscr, y, x, h, w, cache, state):
+    """Files the selected session read, wrote, edited or searched, edited ones first."""
+    active_all = cache.get("active_all", [])
+    rw = x + w - 1
+    vis = state.get("visible_items", [])
+    sel = state.get("selected", -1)
+    sel_item = vis[sel] if 0 <= sel < len(vis) else None
+    target_sid = sel_item.get("session_id", "") if sel_item else ""
+    if not target_sid and active_all:
+        target_sid = active_all[0]["session_id"]
+    files = cache.get("data", {}).get("session_files", {}).get(target_sid, [])
+    state["_tree_len"] = len(files)
+    if not target_sid:
+        safe_add(stdscr, y, x + 2, "select a session", rw, DIM)
+        return
+    if not files:
+        safe_add(stdscr, y, x + 2, "(no files touched)", rw, DIM)
+        return
+
+    sess = cache.get("session_lookup", {}).get(target_sid, {})
+    base = sess.get("git_root") or sess.get("cwd") or ""
+    n_edited = sum(1 for f in files if f["edits"])
+    safe_add(stdscr, y, x + 2, f"{len(files)} files \u00b7 {n_edited} edited" + (f" \u00b7 in {short_path(base)}" if base else ""),
+             rw, DIM)
+
+    avail = max(1, h - 1)
+    cursor = min(state.get("tree_cursor", 0), len(files) - 1)
+    state["tree_cursor"] = cursor
+    scroll = state.get("detail_scroll", 0)
+    if cursor < scroll:
+        scroll = cursor
+    elif cursor >= scroll + avail:
+        scroll = cursor - avail + 1
+    state["detail_scroll"] = scroll
+    focused = state.get("focus") == "right"
+
+    pr = y + 1
+    for idx in range(scroll, min(len(files), scroll + avail)):
+        f = files[idx]
+        counts = "  ".join(f"{k}{n:<3}" for k, n in (("E", f["edits"]), ("R", f["reads"]), ("S", f["searches"])) if n)
+        color = YELLOW if f["edits"] else (WHITE if f["reads"] else DIM)
+        rev = curses.A_REVERSE if focused and idx == cursor else 0
+        if rev:
+            safe_add(stdscr, pr, x + 1, " " * (w - 2), rw, rev)
+        safe_add(stdscr, pr, x + 2, fmt_time(f.get("last_at")), rw, DIM | rev)
+        safe_add(stdscr, pr, x + 11, counts[:16].ljust(16), rw, DIM | rev)
+        safe_add(stdscr, pr, x + 28, short_path(f["path"], base, max(10, w - 31)), rw, color | rev)
+        pr += 1
+
+
+def changeset(diffs: list[dict]) -> list[dict]:
+    """Diff rows aggregated by file: edits, lines added and removed, last change; most recent first."""
+    files: dict[str, dict] = {}
+    for d in diffs:
+        f = files.setdefault(d["path"], {"path": d["path"], "edits": 0, "added": 0, "removed": 0,
+                                         "last_at": d.get("created_at", "")})
+        f["edits"] += 1
+        f["added"] += d.get("added") or 0
+        f["removed"] += d.get("removed") or 0
+        f["last_at"] = max(f["last_at"], d.get("created_at", ""))
+    return sorted(files.values(), key=lambda f: f["last_at"], reverse=True)
+
+
+def load_diff_lines(session_id: str, tool_use_id: str = "", path: str = "") -> list[dict]:
+    """One call's diff, or every diff of a file in the session, as lines tagged with kind and line numbers."""
+    where, args = ("tool_use_id = ?", (tool_use_id,)) if tool_use_id else ("path = ?", (path,))
+    try:
+        conn = connect_ro(DB_PATH)
+        rows = conn.execute(
+            f"""SELECT tool_name, agent_id, diff, added, removed, truncated, created_at FROM file_diff
+                WHERE session_id = ? AND {where} ORDER BY created_at""",
+            (session_id, *args),
+        ).fetchall()
+        conn.close()
+    except sqlite3.Error:
+        return []
+    lines = []
+    for tool_name, agent_id, diff, added, removed, truncated, created_at in rows:
+        if len(rows) > 1:
+            who = f"  agent {short_id(agent_id)}" if agent_id else ""
+            lines.append({"kind": "note", "text": f"\u2500\u2500 {fmt_time(created_at)}  {tool_name}  +{added} \u2212{removed}{who}"})
+        old_no = new_no = 0
+        for text in (diff or "").split("\n"):
+            if text.startswith(("--- ", "+++ ")):
+                if len(rows) == 1:
+                    lines.append({"kind": "file", "text": text})
+                continue
+            m = re.match(r"@@ -(\d+)(?:,\d+)? \+(\d+)", text)
+            if m:
+                old_no, new_no = int(m.group(1)), int(m.group(2))
+                lines.append({"kind": "hunk", "text": text})
+            elif text.startswith("+"):
+                lines.append({"kind": "add", "text": text, "new": new_no})
+                new_no += 1
+            elif text.startswith("-"):
+                lines.append({"kind": "del", "text": text, "old": old_no})
+                old_no += 1
+            else:
+                lines.append({"kind": "ctx", "text": text, "old": old_no, "new": new_no})
+                old_no += 1
+                new_no += 1
+        if truncated:
+            lines.append({"kind": "note", "text": "\u2026 diff truncated"})
+    return lines
+
+
+def open_diff(state: dict, title: str, session_id: str, tool_use_id: str = "", path: str = "") -> None:
+    """Show a diff over the right panel; Esc closes it."""
+    lines = load_diff_lines(session_id, tool_use_id, path)
+    if not lines:
+        state["status_msg"] = "no diff recorded"
+        state["status_until"] = time.time() + 3
+        return
+    state["diff_view"] = {"title": title, "lines": lines, "scroll": 0}
+
+
+def _draw_diff_view(stdscr, y, x, h, w, state):
+    """Scrollable unified diff with old/new line numbers, coloured by line kind."""
+    dv = state["diff_view"]
+    rw = x + w - 1
+    lines = dv["lines"]
+    state["_diff_page"] = max(1, h - 1)
+    dv["scroll"] = max(0, min(dv["scroll"], len(lines) - h))
+    colors = {"file": WHITE, "hunk": CYAN, "add": GREEN, "del": RED, "ctx": DIM, "note": MAGENTA}
+    pr = y
+    for ln in lines[dv["scroll"]:dv["scroll"] + h]:
+        kind = ln["kind"]
+        if kind in ("add", "del", "ctx"):
+            old = str(ln["old"]) if "old" in ln else ""
+            new = str(ln["new"]) if "new" in ln else ""
+            safe_add(stdscr, pr, x + 2, f"{old:>5} {new:>5} \u2502", rw, DIM)
+            safe_add(stdscr, pr, x + 15, ln["text"].expandtabs(4)[:max(0, w - 17)], rw, colors[kind])
+        else:
+            safe_add(stdscr, pr, x + 2, ln["text"][:max(0, w - 4)], rw, colors[kind] | curses.A_BOLD)
+        pr += 1
+    if len(lines) > h:
+        pos = f" {dv['scroll'] + 1}-{min(len(lines), dv['scroll'] + h)}/{len(lines)} "
+        safe_add(stdscr, y + h, x + w - len(pos) - 2, pos, rw, DIM)
+
+
+def _draw_viz_changes(stdscr, y, x, h, w, cache, state):
+    """Changeset: every file the selected session edited, with its edits and lines added and removed."""
+    active_all = cache.get("active_all", [])
+    rw = x + w - 1
+    vis = state.get("visible_items", [])
+    sel = state.get("selected", -1)
+    sel_item = vis[sel] if 0 <= sel < len(vis) else None
+    target_sid = sel_item.get("session_id", "") if sel_item else ""
+    if not target_sid and active_all:
+        target_sid = active_all[0]["session_id"]
+    files = changeset(cache.get("data", {}).get("session_diffs", {}).get(target_sid, []))
+    state["_tree_len"] = len(files)
+    state["_changes"] = (target_sid, files)
+    if not target_sid:
+        safe_add(stdscr, y, x + 2, "select a session", rw, DIM)
+        return
+    if not files:
+        safe_add(stdscr, y, x + 2, "(no edits recorded)", rw, DIM)
+        return
+
+    sess = cache.get("session_lookup", {}).get(target_sid, {})
+    base = sess.get("git_root") or sess.get("cwd") or ""
+    added = sum(f["added"] for f in files)
+    removed = sum(f["removed"] for f in files)
+    safe_add(stdscr, y, x + 2, f"{len(files)} file{'s' if len(files) != 1 else ''} changed \u00b7 +{added} \u2212{removed}",
+             rw, DIM)
+
+    avail = max(1, h - 1)
+    cursor = min(state.get("tree_cursor", 0), len(files) - 1)
+    state["tree_cursor"] = cursor
+    scroll = state.get("detail_scroll", 0)
+    if cursor < scroll:
+        scroll = cursor
+    elif cursor >= scroll + avail:
+        scroll = cursor - avail + 1
+    state["detail_scroll"] = scroll
+    focused = state.get("focus") == "right"
+
+    pr = y + 1
+    for idx in range(scroll, min(len(files), scroll + avail)):
+        f = files[idx]
+        rev = curses.A_REVERSE if focused and idx == cursor else 0
+        if rev:
+            safe_add(stdscr, pr, x + 1, " " * (w - 2), rw, rev)
+        safe_add(stdscr, pr, x + 2, fmt_time(f.get("last_at")), rw, DIM | rev)
+        safe_add(stdscr, pr, x + 11, f"\u00d7{f['edits']:<3}", rw, DIM | rev)
+        safe_add(stdscr, pr, x + 16, f"+{f['added']}".rjust(6), rw, GREEN | rev)
+        safe_add(stdscr, pr, x + 23, f"\u2212{f['removed']}".ljust(6), rw, RED | rev)
+        safe_add(stdscr, pr, x + 30, short_path(f["path"], base, max(10, w - 33)), rw, YELLOW | rev)
+        pr += 1
+
+
+def select_session(state: dict, session_id: str) -> bool:
+    """Select a session (or failing that, one of its agents) in the left panel and show its tree."""
+    items = state.get("visible_items", [])
+    matches = [i for i, item in enumerate(items) if session_id and item.get("session_id") == session_id]
+    matches.sort(key=lambda i: not (items[i].get("is_session") or items[i].get("is_teammate")))
+    if not matches:
+        return False
+    state["selected"] = matches[0]
+    state["viz_mode"] = VIZ_MODES.index("tree")
+    state["focus"] = "right"
+    state["detail_scroll"] = 0; state["tree_cursor"] = 0
+    state["_expanded_tool"] = -1
+    return True
+
+
 def _format_smart_summary(tool_name, raw_response, max_lines=8, max_width=70):
     """Parse tool response into a smart summary based on tool type."""
     if not raw_response:
@@ -972,12 +1868,21 @@ def _render_tool_expansion(stdscr, ev, pr, col, rw, max_row, is_error=False):
             safe_add(stdscr, row, col, text[:max_width + 4], rw, c)
             rows_drawn += 1
 
-    # Top separator
-    draw(pr, f"  {separator}", color)
+    # Top separator, with how many secrets ccnotify masked in this call
+    redacted = ev.get("_redactions", 0)
+    if redacted:
+        note = f" {redacted} secret{'s' if redacted != 1 else ''} redacted "
+        draw(pr, f"  {separator[:max(0, len(separator) - len(note) - 2)]}{note}\u254c\u254c", color)
+    else:
+        draw(pr, f"  {separator}", color)
     pr += 1
 
+    # Rows stored before redaction existed get masked here instead
+    def masked(text):
+        return _ccnotify.redact_text(text)[0] if isinstance(text, str) else text
+
     # Input section — pretty-printed JSON
-    raw_input = ev.get("_raw_input")
+    raw_input = masked(ev.get("_raw_input"))
     if raw_input:
         try:
             ti = json.loads(raw_input) if isinstance(raw_input, str) else raw_input
@@ -991,7 +1896,7 @@ def _render_tool_expansion(stdscr, ev, pr, col, rw, max_row, is_error=False):
 
     # Response/Error section
     if is_error:
-        err_msg = ev.get("_error_message", "")
+        err_msg = masked(ev.get("_error_message", ""))
         if err_msg:
             draw(pr, "  ERROR", RED | curses.A_BOLD)
             pr += 1
@@ -999,13 +1904,17 @@ def _render_tool_expansion(stdscr, ev, pr, col, rw, max_row, is_error=False):
                 draw(pr, f"  {line[:max_width]}", RED)
                 pr += 1
     else:
-        raw_resp = ev.get("_raw_response")
+        raw_resp = masked(ev.get("_raw_response"))
         tool_name = ev.get("_tool_name", "")
         summary_lines = _format_smart_summary(tool_name, raw_resp, max_lines=8, max_width=max_width - 4)
         for line in summary_lines:
             draw(pr, f"  \u2192 {line}", resp_color)
             pr += 1
 
+    if ev.get("_diff_path"):
+        draw(pr, "  d = view diff", CYAN)
+        pr += 1
+
     # Bottom separator
     draw(pr, f"  {separator}", color)
     rows_drawn += 1
@@ -1014,7 +1923,12 @@ def _render_tool_expansion(stdscr, ev, pr, col, rw, max_row, is_error=False):
 
 
 def _match_tools_to_agents(tools, agents, target_sid):
-    """Match tool_events to agents by session_id + time window + cwd.
+    """Match tool_events to agents, by the ids ccnotify recorded where it could.
+
+    Tools carry the agent they ran in (tool_event.agent_id) and agents the Task
+    call that spawned them (agent.tool_use_id). Sessions recorded before that —
+    no tool has an agent_id — fall back to session_id + time window + cwd, and
+    agents whose Task call hasn't returned yet are paired with one by proximity.
 
     Returns:
         agent_tools: dict[agent_id] -> [tool_event_dicts]  (tools belonging to each agent)
@@ -1033,14 +1947,24 @@ def _match_tools_to_agents(tools, agents, target_sid):
     agent_labels = {}
     unmatched = []
 
-    # Pair Task tool_events to agents 1:1 by timestamp proximity + type hint.
+    # Task calls linked to their agent at PostToolUse
+    task_pool = [t for t in tools if t.get("_tool_name") == "Task"]
+    used_tasks = set()
+    by_use_id = {tt["_tool_use_id"]: i for i, tt in enumerate(task_pool) if tt.get("_tool_use_id")}
+    for agent in session_agents:
+        i = by_use_id.get(agent.get("tool_use_id") or "")
+        if i is not None:
+            used_tasks.add(i)
+            agent_labels[agent["agent_id"]] = task_pool[i].get("text", agent.get("agent_type", "agent"))
+
+    # Pair the remaining Task tool_events to agents 1:1 by timestamp proximity + type hint.
     # Task fires just before SubagentStart; once matched, remove from pool.
     from datetime import datetime as _dt
-    task_pool = [t for t in tools if t.get("_tool_name") == "Task"]
     # Sort agents by start time so earliest agents match earliest Tasks
     sorted_agents = sorted(session_agents, key=lambda a: a.get("started_at", ""))
-    used_tasks = set()
     for agent in sorted_agents:
+        if agent["agent_id"] in agent_labels:
+            continue
         a_start = agent.get("started_at", "")
         a_type = agent.get("agent_type", "")
         best_task = None
@@ -1070,10 +1994,20 @@ def _match_tools_to_agents(tools, agents, target_sid):
             used_tasks.add(idx)
             agent_labels[agent["agent_id"]] = tt.get("text", agent.get("agent_type", "agent"))
 
-    # Assign non-Task tools to agents by time window + cwd
+    # Tools that recorded their agent: exact. Once a session has any, the rest
+    # ran in the main thread.
+    attributed = any(t.get("_agent_id") for t in tools)
+    headers = {id(task_pool[i]) for i in used_tasks}
     for t in tools:
-        if t.get("_tool_name") == "Task":
+        if t.get("_tool_name") == "Task" and (not t.get("_agent_id") or id(t) in headers):
             continue  # Task tool_events become agent group headers, not children
+        if attributed:
+            if t.get("_agent_id") in agent_tools:
+                agent_tools[t["_agent_id"]].append(t)
+            else:
+                unmatched.append(t)
+            continue
+        # Legacy rows: assign by time window + cwd
         t_ts = t.get("ts", "")
         t_cwd = t.get("_cwd", "")
         candidates = []
@@ -1101,14 +2035,38 @@ def _match_tools_to_agents(tools, agents, target_sid):
     return agent_tools, agent_labels, unmatched
 
 
+def _count_descendants(group: dict) -> int:
+    """Set _child_count on an agent group and its nested groups: every row beneath it."""
+    total = 0
+    for c in group.get("_children", []):
+        total += 1 + (_count_descendants(c) if c.get("kind") == "agent_group" else 0)
+    group["_child_count"] = total
+    return total
+
+
+def _expand_tree_item(ev: dict, depth: int, out: list, collapsed_agents: set) -> None:
+    """Append a prompt child to the tree, recursing into agent groups that aren't collapsed."""
+    ev["_depth"] = depth
+    out.append(ev)
+    if ev.get("kind") != "agent_group":
+        return
+    ev["_collapsed"] = ev.get("_agent_id", "") in collapsed_agents
+    if not ev["_collapsed"]:
+        for child in ev.get("_children", []):
+            _expand_tree_item(child, depth + 1, out, collapsed_agents)
+
+
 def _draw_viz_tree(stdscr, y, x, h, w, cache, state):
     """Interleaved timeline: prompts, tools, and agents in chronological order (newest first)."""
     active_all = cache.get("active_all", [])
     r_agents = cache.get("r_agents", [])
-    c_agents = cache.get("c_agents", [])
+    c_agents = cache.get("c_agents", []) + cache.get("loaded_agents", [])
     session_tools = cache.get("session_tools", {})
     tool_events = cache.get("tool_events", {})
     session_prompts = cache.get("session_prompts", {})
+    session_events = cache.get("session_events", {})
+    session_compactions = cache.get("session_compactions", {})
+    agent_usage = cache.get("data", {}).get("agent_usage", {})
     rw = x + w - 1  # absolute right edge minus border
 
     # Scope to selected session
@@ -1131,9 +2089,13 @@ def _draw_viz_tree(stdscr, y, x, h, w, cache, state):
             continue
         timeline.append({"ts": p.get("created_at", ""), "kind": "prompt", "text": prompt_text})
     # Tools
+    diff_stats = {d["tool_use_id"]: d for d in cache.get("data", {}).get("session_diffs", {}).get(target_sid, [])}
     for t in (session_tools.get(target_sid, []) or tool_events.get(target_sid, [])):
         dur_ms = t.get("duration_ms")
-        desc = friendly_tool(t["tool_name"], t.get("tool_label", ""))
+        desc = friendly_tool(t["tool_name"], _ccnotify.redact_text(t.get("tool_label") or "")[0])
+        diff = diff_stats.get(t.get("tool_use_id")) if t.get("tool_use_id") else None
+        if diff:
+            desc += f"  +{diff['added']} \u2212{diff['removed']}"
         is_err = bool(t.get("is_error"))
         timeline.append({
             "ts": t.get("created_at", ""),
@@ -1146,10 +2108,16 @@ def _draw_viz_tree(stdscr, y, x, h, w, cache, state):
             "_is_error": is_err,
             "_error_message": t.get("error_message", ""),
             "_cwd": t.get("cwd", ""),
+            "_redactions": t.get("redactions") or 0,
+            "_tool_use_id": t.get("tool_use_id"),
+            "_agent_id": t.get("agent_id"),
+            "_diff_path": diff["path"] if diff else None,
         })
     # Agents — variable defs only; rendered as agent_groups below
     children = [a for a in r_agents if a["session_id"] == target_sid and a.get("agent_type")]
-    completed = [a for a in c_agents if a["session_id"] == target_sid and a.get("agent_type")][:5]
+    completed = [a for a in c_agents if a["session_id"] == target_sid and a.get("agent_type")]
+    if target_sid in cache.get("session_lookup", {}):
+        completed = completed[:5]  # a live session shows its latest few; a past one all of them
 
     # Match tools to agents
     all_agents = r_agents + c_agents
@@ -1179,17 +2147,44 @@ def _draw_viz_tree(stdscr, y, x, h, w, cache, state):
             display_label = f"{label} #{seq}"
         else:
             display_label = label
+        usage = usage_label(agent_usage.get(aid))
         agent_group_events.append({
             "ts": a.get("started_at", ""),
             "kind": "agent_group",
-            "text": f"{display_label}  {adur}",
+            "text": f"{display_label}  {adur}" + (f"  {usage}" if usage else ""),
             "running": running,
             "_agent_id": aid,
-            "_children": a_tools,
-            "_child_count": len(a_tools),
+            "_parent_id": a.get("parent_agent_id"),
+            "_children": list(a_tools),
         })
 
-    # Merge: prompts, unmatched tools, agent groups — sorted chronologically
+    # Nest agents under the agent that spawned them, when it is listed too
+    groups_by_id = {g["_agent_id"]: g for g in agent_group_events}
+    top_groups = []
+    for g in agent_group_events:
+        parent = groups_by_id.get(g["_parent_id"])
+        if parent is not None and parent is not g:
+            parent["_children"].append(g)
+        else:
+            top_groups.append(g)
+    for g in agent_group_events:
+        g["_children"].sort(key=lambda e: e.get("ts", ""))
+    for g in top_groups:
+        _count_descendants(g)
+    agent_group_events = top_groups
+
+    # Compaction markers and hook events without a handler
+    markers = []
+    for c in session_compactions.get(target_sid, []):
+        text = f"context compacted ({c.get('trigger') or '?'})"
+        if c.get("custom_instructions"):
+            text += f"  {c['custom_instructions'].replace(chr(10), ' ')}"
+        markers.append({"ts": c.get("created_at", ""), "kind": "compact", "text": text})
+    for e in session_events.get(target_sid, []):
+        text = e["event"] + (f"  {e['summary']}" if e.get("summary") else "")
+        markers.append({"ts": e.get("created_at", ""), "kind": "event", "text": text})
+
+    # Merge: prompts, unmatched tools, agent groups, markers — sorted chronologically
     merged = []
     for ev in timeline:
         if ev["kind"] == "prompt":
@@ -1198,6 +2193,7 @@ def _draw_viz_tree(stdscr, y, x, h, w, cache, state):
         merged.append(ev)
     for ev in agent_group_events:
         merged.append(ev)
+    merged.extend(markers)
     merged.sort(key=lambda e: e.get("ts", ""))
 
     # Group by prompt: events AFTER a prompt are its children
@@ -1237,17 +2233,7 @@ def _draw_viz_tree(stdscr, y, x, h, w, cache, state):
             timeline.append(prompt_ev)
             if prompt_key not in collapsed:
                 for child in prompt_children:
-                    if child.get("kind") == "agent_group":
-                        aid = child.get("_agent_id", "")
-                        is_collapsed = aid in collapsed_agents
-                        child["_collapsed"] = is_collapsed
-                        timeline.append(child)
-                        if not is_collapsed:
-                            for agent_tool in child.get("_children", []):
-                                agent_tool["_under_agent"] = True
-                                timeline.append(agent_tool)
-                    else:
-                        timeline.append(child)
+                    _expand_tree_item(child, 0, timeline, collapsed_agents)
         else:
             timeline.extend(prompt_children)
 
@@ -1288,8 +2274,10 @@ def _draw_viz_tree(stdscr, y, x, h, w, cache, state):
     visible = timeline[scroll:scroll + visible_rows]
 
     pr = y
-    kind_colors = {"prompt": WHITE, "tool": YELLOW, "agent": MAGENTA, "agent_group": MAGENTA}
-    kind_icons = {"prompt": "\u25b8", "tool": "\u2502", "agent": "\u25c6", "agent_group": "\u25c6"}
+    kind_colors = {"prompt": WHITE, "tool": YELLOW, "agent": MAGENTA, "agent_group": MAGENTA,
+                   "compact": CYAN, "event": DIM}
+    kind_icons = {"prompt": "\u25b8", "tool": "\u2502", "agent": "\u25c6", "agent_group": "\u25c6",
+                  "compact": "\u27f3", "event": "\u00b7"}
     focused = state.get("focus") == "right"
 
     # Find which group the cursor belongs to
@@ -1335,13 +2323,11 @@ def _draw_viz_tree(stdscr, y, x, h, w, cache, state):
             color = RED
 
         ts = fmt_time(ev.get("ts", ""))
-        # Indent: prompt=0, agent_group/tool=2, tool-under-agent=4
+        # Indent: prompt=0, then 2 per level (agent_group/tool=2, inside an agent=4, ...)
         if kind == "prompt":
             indent = 0
-        elif ev.get("_under_agent"):
-            indent = 4
         else:
-            indent = 2
+            indent = 2 + 2 * min(ev.get("_depth", 0), 6)
         col_start = x + 2 + indent
         icon_col = x + 11 + indent
         text_w = w - 15 - indent  # available width for text after icon (minus borders)
@@ -1419,7 +2405,7 @@ def _unused_graph():
     cache_key = f"{target_sid}:{frame // DATA_FRAMES}"  # refresh with data cycle
     if graph_cache.get("key") != cache_key:
         try:
-            conn = sqlite3.connect(DB_PATH)
+            conn = connect_ro()
             conn.row_factory = sqlite3.Row
             prompts = conn.execute(
                 "SELECT prompt, created_at, seq FROM prompt WHERE session_id = ? ORDER BY seq ASC LIMIT 10",
@@ -1598,8 +2584,10 @@ def member_status(activity_buckets, session):
     """Return (icon, color) based on member activity."""
     recent = activity_buckets[-5:] if activity_buckets else []
     has_recent_tools = any(b > 0 for b in recent)
-    waiting = bool(session.get("lastWaitUserAt"))
-    if has_recent_tools:
+    waiting = session_waiting(session)
+    if session.get("state") in _ccnotify.BLOCKED_STATES:
+        return ("○", DIM)  # blocked on the user, whatever ran just before
+    elif has_recent_tools:
         return ("◉", GREEN)
     elif waiting:
         return ("○", DIM)
@@ -1631,9 +2619,9 @@ def draw_box(stdscr, y, x, h, w, title="", title_attr=0, border_attr=0):
 
 # ── MAIN DRAW + LOOP ────────────────────────────────────────
 
-def refresh_data(cache: dict, stats_range_idx: int = 2) -> dict:
+def refresh_data(cache: dict, stats_range_idx: int = 2, stats_group_idx: int = 0) -> dict:
     """Refresh cached dashboard data from DB + filesystem."""
-    data = query_db(DB_PATH, stats_range_idx)
+    data = query_db(DB_PATH, stats_range_idx, stats_group_idx)
     data["_stats_range_idx"] = stats_range_idx
     active_all = data["active_sessions"]
     active_sids_all = {s["session_id"] for s in active_all}
@@ -1647,19 +2635,68 @@ def refresh_data(cache: dict, stats_range_idx: int = 2) -> dict:
         "tool_events": data["tool_events"],
         "session_tools": data["session_tools"],
         "session_prompts": data["session_prompts"],
+        "session_events": data["session_events"],
+        "session_compactions": data["session_compactions"],
+        "session_conflicts": data["session_conflicts"],
         "activity": data["activity"],
         "team_data": team_data,
         "session_lookup": {s["session_id"]: s for s in active_all},
+        "history": None,  # re-queried by the HISTORY browser on its next draw
     })
+    _merge_loaded_session(cache)
     return cache
 
 
+def open_session(cache: dict, session_id: str) -> None:
+    """Make a session that is no longer active drawable by TREE, TIMELINE and DETAIL."""
+    if (cache.get("loaded_session") or {}).get("session_id") != session_id:
+        cache["loaded_session"] = load_session(DB_PATH, session_id)
+        _merge_loaded_session(cache)
+
+
+def _merge_loaded_session(cache: dict) -> None:
+    """Fold the loaded past session into the per-session dicts the live refresh just rebuilt."""
+    loaded = cache.get("loaded_session")
+    if not loaded or loaded["session_id"] in cache["session_lookup"]:
+        # Resumed since it was loaded: the live rows win, and it is read again once it ends
+        cache.pop("loaded_session", None)
+        cache["loaded_agents"] = []
+        return
+    for key in SESSION_DETAIL_KEYS:
+        cache["data"][key].update(loaded[key])
+    listed = {a["agent_id"] for a in cache["c_agents"]}
+    cache["loaded_agents"] = [a for a in loaded["agents"] if a["agent_id"] not in listed]
+
+
+def history_sessions(cache: dict, state: dict) -> tuple[list[dict], list[str]]:
+    """The HISTORY browser's sessions after all filters, and the projects `p` cycles through."""
+    key = (state.get("hist_range", 2), state.get("hist_dur", 0), state.get("hist_agents", 0))
+    if cache.get("history") is None or cache.get("_history_key") != key:
+        cache["history"] = query_history(DB_PATH, key[0], HISTORY_DURATIONS[key[1]][1], HISTORY_AGENTS[key[2]][1])
+        cache["_history_key"] = key
+    rows = cache["history"]
+    # Projects by how many sessions they have, busiest first
+    counts: dict[str, int] = {}
+    for r in rows:
+        counts[r["cwd"] or ""] = counts.get(r["cwd"] or "", 0) + 1
+    projects = sorted(counts, key=lambda c: -counts[c])
+    if state.get("hist_project") is not None:
+        rows = [r for r in rows if (r["cwd"] or "") == state["hist_project"]]
+    return rows, projects
+
+
 def _detail_content_height(sel_agent, pw, cache):
     """Estimate how many content rows DETAIL needs (excluding box borders)."""
     if not sel_agent:
         return 3
     if sel_agent.get("is_session"):
         rows = 2  # header + divider
+        if location_line(sel_agent.get("location")):
+            rows += 1
+        if context_line(sel_agent):
+            rows += 1
+        if state_line(sel_agent):
+            rows += 1
         prompt = (sel_agent.get("prompt", "") or "").replace("\n", " ").strip()
         if prompt:
             rows += 1  # prompt line
@@ -1693,14 +2730,29 @@ def _draw_detail(stdscr, pr, col, max_row, max_col, sel_agent, cache, scroll=0):
     if sel_agent and sel_agent.get("is_session"):
         # Rich session detail
         sid = sel_agent["session_id"]
-        dur = fmt_dur(sel_agent.get("started_at", ""))
+        dur = fmt_dur(sel_agent.get("started_at", ""), sel_agent.get("stopped_at"))
         sid_short = sid[:7]
         cwd = sel_agent.get("cwd", "")
         prompt_text = sel_agent.get("prompt", "")
         tag = dir_tag(cwd)
         header = f"{sid_short} \u00b7 {tag} session \u00b7 {dur}" if tag else f"{sid_short} \u00b7 session \u00b7 {dur}"
+        usage = usage_label(cache.get("data", {}).get("session_usage", {}).get(sid))
+        if usage:
+            header += f" \u00b7 {usage}"
         P(pr, col, header[:pw], GREEN | curses.A_BOLD)
         pr += 1
+        ctx_text = context_line(sel_agent)
+        if ctx_text:
+            P(pr, col, ctx_text[:pw], DIM)
+            pr += 1
+        st_text = state_line(sel_agent)
+        if st_text:
+            P(pr, col, st_text[:pw], state_label(sel_agent.get("state"))[1])
+            pr += 1
+        loc_text = location_line(sel_agent.get("location"))
+        if loc_text:
+            P(pr, col, loc_text[:pw], DIM)
+            pr += 1
         P(pr, col, SYMBOLS["h"] * pw, DIM)
         pr += 1
 
@@ -1814,13 +2866,16 @@ def _draw_detail(stdscr, pr, col, max_row, max_col, sel_agent, cache, scroll=0):
                 P(pr, col, f"{icon}  {subject}{owner_tag}", attr)
                 pr += 1
 
-    elif sel_agent:
+    elif sel_agent and not sel_agent.get("is_stat"):
         # Live preview of selected subagent
         dur = fmt_dur(sel_agent["started_at"])
         atype = sel_agent.get("agent_type") or "agent"
         aid = sel_agent["agent_id"][:7]
         tag = dir_tag(sel_agent.get("cwd", ""))
         header = f"{aid} \u00b7 {tag} {atype} \u00b7 {dur}" if tag else f"{aid} \u00b7 {atype} \u00b7 {dur}"
+        usage = usage_label(cache.get("data", {}).get("agent_usage", {}).get(sel_agent["agent_id"]))
+        if usage:
+            header += f" \u00b7 {usage}"
         P(pr, col, header[:pw], MAGENTA | curses.A_BOLD)
         pr += 1
         P(pr, col, SYMBOLS["h"] * pw, DIM)
@@ -1847,32 +2902,61 @@ def _draw_detail(stdscr, pr, col, max_row, max_col, sel_agent, cache, scroll=0):
 
         # Query recent instances from DB (cached per selection)
         stat_cache = cache.setdefault("_stat_drill", {})
-        stat_key = f"{kind}:{atype}:{cwd}"
+        group_col, group_val = sel_agent.get("group_col"), sel_agent.get("group_val")
+        stat_key = f"{kind}:{atype}:{cwd}:{group_col}:{group_val}"
+
+        def group_filter(sid_expr: str) -> str:
+            return f"AND ({session_attr_sql(group_col, sid_expr)}) IS ?" if group_col else ""
+        group_args = (group_val,) if group_col else ()
         if stat_cache.get("key") != stat_key:
             stat_cache["key"] = stat_key
             stat_cache["data"] = None
             try:
-                conn = sqlite3.connect(DB_PATH)
+                conn = connect_ro()
                 conn.row_factory = sqlite3.Row
                 range_idx = cache.get("data", {}).get("_stats_range_idx", 2)
                 _, sql_interval = STATS_RANGES[min(range_idx, len(STATS_RANGES) - 1)]
                 if kind == "agent":
                     time_filter = f"AND started_at > datetime('now', '{sql_interval}')" if sql_interval else ""
-                    cwd_filter = f"AND cwd = '{cwd}'" if cwd else ""
+                    cwd_filter = f"AND cwd = '{cwd}'" if cwd else group_filter("agent.session_id")
                     stat_cache["data"] = {"rows": [dict(r) for r in conn.execute(
                         f"SELECT agent_id, agent_type, cwd, started_at, stopped_at FROM agent WHERE agent_type = ? {time_filter} {cwd_filter} ORDER BY started_at DESC LIMIT 8",
-                        (atype,)).fetchall()]}
+                        (atype, *group_args)).fetchall()]}
                 elif kind == "tool":
                     time_filter = f"AND te.created_at > datetime('now', '{sql_interval}')" if sql_interval else ""
-                    cwd_filter = f"AND p.cwd = '{cwd}'" if cwd else ""
+                    cwd_filter = f"AND COALESCE(({SESSION_CWD_SQL}), te.cwd) = '{cwd}'" if cwd else group_filter("te.session_id")
                     stat_cache["data"] = {
                         "labels": [dict(r) for r in conn.execute(
-                            f"SELECT te.tool_label, COUNT(*) as cnt FROM tool_event te LEFT JOIN prompt p ON te.session_id = p.session_id WHERE te.tool_name = ? {time_filter} {cwd_filter} GROUP BY te.tool_label ORDER BY cnt DESC LIMIT 8",
-                            (atype,)).fetchall()],
+                            f"SELECT te.tool_label, COUNT(*) as cnt FROM tool_event te WHERE te.tool_name = ? {time_filter} {cwd_filter} GROUP BY te.tool_label ORDER BY cnt DESC LIMIT 8",
+                            (atype, *group_args)).fetchall()],
                         "recent": [dict(r) for r in conn.execute(
-                            f"SELECT te.tool_label, te.created_at, te.session_id FROM tool_event te LEFT JOIN prompt p ON te.session_id = p.session_id WHERE te.tool_name = ? {time_filter} {cwd_filter} ORDER BY te.created_at DESC LIMIT 6",
-                            (atype,)).fetchall()],
+                            f"SELECT te.tool_label, te.created_at, te.session_id FROM tool_event te WHERE te.tool_name = ? {time_filter} {cwd_filter} ORDER BY te.created_at DESC LIMIT 6",
+                            (atype, *group_args)).fetchall()],
                     }
+                elif kind == "error":
+                    time_filter = f"AND te.created_at > datetime('now', '{sql_interval}')" if sql_interval else ""
+                    cwd_filter = f"AND COALESCE(({SESSION_CWD_SQL}), te.cwd) = '{cwd}'" if cwd else group_filter("te.session_id")
+                    stat_cache["data"] = {"recent": [dict(r) for r in conn.execute(
+                        f"SELECT te.id, te.tool_label, te.error_message, te.created_at, te.session_id FROM tool_event te WHERE te.tool_name = ? AND te.is_error = 1 {time_filter} {cwd_filter} ORDER BY te.created_at DESC LIMIT 8",
+                        (atype, *group_args)).fetchall()]}
+                elif kind == "file":
+                    time_filter = f"AND created_at > datetime('now', '{sql_interval}')" if sql_interval else ""
+                    stat_cache["data"] = {"recent": [dict(r) for r in conn.execute(
+                        f"""SELECT session_id, agent_id, op, created_at FROM file_touch
+                            WHERE path = ? {time_filter} ORDER BY created_at DESC LIMIT 10""",
+                        (atype,)).fetchall()]}
+                elif kind == "spend":
+                    time_filter = f"AND u.created_at > datetime('now', '{sql_interval}')" if sql_interval else ""
+                    session_cwd = (session_attr_sql(group_col, "u.session_id") if group_col
+                                   else SESSION_CWD_SQL.replace("te.", "u."))
+                    stat_cache["data"] = {"sessions": [dict(r) for r in conn.execute(
+                        f"""SELECT u.session_id, SUM({USAGE_TOKENS_SQL}) as tokens, SUM(u.cost_usd) as cost,
+                                   (SELECT p.prompt FROM prompt p WHERE p.session_id = u.session_id AND p.prompt NOT LIKE '<%'
+                                    ORDER BY p.id DESC LIMIT 1) as prompt
+                            FROM usage u
+                            WHERE u.model IS ? AND ({session_cwd}) IS ? {time_filter}
+                            GROUP BY u.session_id ORDER BY cost DESC, tokens DESC LIMIT 8""",
+                        (sel_agent.get("model"), group_val if group_col else cwd or None)).fetchall()]}
                 conn.close()
             except Exception:
                 stat_cache["data"] = None
@@ -1938,6 +3022,52 @@ def _draw_detail(stdscr, pr, col, max_row, max_col, sel_agent, cache, scroll=0):
                         pr += 1
             else:
                 P(pr, col, "(no recent uses)", DIM)
+        elif sd and kind == "file":
+            recent_rows = sd.get("recent", [])
+            if recent_rows:
+                P(pr, col, "RECENT TOUCHES", CYAN)
+                pr += 1
+                for rr in recent_rows:
+                    if pr >= max_row_virtual:
+                        break
+                    who = short_session(rr["session_id"]) + (f"/{short_id(rr['agent_id'])}" if rr.get("agent_id") else "")
+                    P(pr, col, f"{fmt_time(rr['created_at'])}  {rr['op']:<6}  {who}", YELLOW if rr["op"] != "read" else DIM)
+                    pr += 1
+            else:
+                P(pr, col, "(no recent edits)", DIM)
+        elif sd and kind == "error":
+            recent_rows = sd.get("recent", [])
+            if recent_rows:
+                P(pr, col, "RECENT FAILURES", RED)
+                pr += 1
+                for rr in recent_rows:
+                    if pr >= max_row_virtual:
+                        break
+                    ts = fmt_time(rr["created_at"])
+                    tl = rr["tool_label"] or ""
+                    sid = short_session(rr["session_id"])
+                    P(pr, col, f"{ts}  {sid}  {tl[:pw - 18]}", DIM)
+                    pr += 1
+                    err = (rr.get("error_message") or "").replace("\n", " ").strip()
+                    if err and pr < max_row_virtual:
+                        P(pr, col, f"  \u2717 {err[:pw - 4]}", RED)
+                        pr += 1
+            else:
+                P(pr, col, "(no recent failures)", DIM)
+        elif sd and kind == "spend":
+            rows = sd.get("sessions", [])
+            if rows:
+                P(pr, col, "TOP SESSIONS", CYAN)
+                pr += 1
+                for rr in rows:
+                    if pr >= max_row_virtual:
+                        break
+                    sid = short_session(rr["session_id"])
+                    prompt = short_prompt(rr.get("prompt"), max(0, pw - 28))
+                    P(pr, col, f"{sid}  {fmt_cost(rr['cost'] or 0):>7}  {fmt_tokens(rr['tokens'] or 0):>6}  {prompt}", DIM)
+                    pr += 1
+            else:
+                P(pr, col, "(no usage)", DIM)
         else:
             P(pr, col, "(no data)", DIM)
 
@@ -1947,7 +3077,85 @@ def _draw_detail(stdscr, pr, col, max_row, max_col, sel_agent, cache, scroll=0):
         pr += 1
         P(pr, col, "esc  deselect", DIM)
         pr += 1
-        P(pr, col, "ret  open in iTerm2", DIM)
+        P(pr, col, "ret  open detail", DIM)
+        pr += 1
+        P(pr, col, "g    jump to pane", DIM)
+
+
+def _draw_history_browser(stdscr, y, h, w, state, cache, visible_items) -> str:
+    """HISTORY mode's left panel: ended sessions after the filters, each selectable like a live one.
+
+    Returns the panel title.
+    """
+    rows, _ = history_sessions(cache, state)
+    title = f"HISTORY  {len(rows)} ended session{'s' if len(rows) != 1 else ''}"
+    draw_box(stdscr, y, 0, h, w, title=title)
+    rw = w - 1
+    proj = state.get("hist_project")
+    filters = [
+        ("p", "all projects" if proj is None else (dir_tag(proj) or "(no cwd)")),
+        ("t", STATS_RANGES[state.get("hist_range", 2)][0]),
+        ("u", HISTORY_DURATIONS[state.get("hist_dur", 0)][0]),
+        ("n", HISTORY_AGENTS[state.get("hist_agents", 0)][0]),
+    ]
+    col = 2
+    for key, label in filters:
+        safe_add(stdscr, y + 1, col, key, rw, CYAN)
+        safe_add(stdscr, y + 1, col + 2, label, rw, WHITE)
+        col += len(label) + 5
+    select_first = state.pop("_hist_select_first", False)
+    if not rows:
+        safe_add(stdscr, y + 2, 2, "(no ended sessions match)", rw, DIM)
+        return title
+
+    # Every row is selectable; only the window around the selection is drawn
+    n_rows = max(1, h - 3)
+    first_idx = len(visible_items)
+    if select_first:
+        state["selected"] = first_idx
+    sel = state.get("selected", -1) - first_idx
+    top = state.get("_hist_top", 0)
+    if 0 <= sel < top:
+        top = sel
+    elif sel >= top + n_rows:
+        top = sel - n_rows + 1
+    top = max(0, min(top, len(rows) - n_rows))
+    state["_hist_top"] = top
+    for i, r in enumerate(rows):
+        visible_items.append({
+            "agent_id": r["session_id"],
+            "agent_type": "session",
+            "session_id": r["session_id"],
+            "started_at": r["started_at"],
+            "stopped_at": r["stopped_at"],
+            "cwd": r["cwd"] or "",
+            "is_session": True,
+            "is_history": True,
+            "prompt": r["prompt"] or "",
+            "location": {},
+            **{k: r.get(k) for k in SESSION_CONTEXT_KEYS},
+        })
+        if not top <= i < top + n_rows:
+            continue
+        hr = y + 2 + i - top
+        is_sel = i == sel
+        when = (r["started_at"] or "")[5:16]
+        dur = fmt_dur_seconds(r["secs"] or 0)
+        agents = f"\u25c6{r['agents']}" if r["agents"] else ""
+        tag = dir_tag(r["cwd"] or "")
+        prompt = short_prompt(r["prompt"], max(10, w - 31 - len(tag))) or "(no prompt)"
+        if is_sel:
+            safe_add(stdscr, hr, 1, " " * (w - 2), rw, SEL_DIM)
+        safe_add(stdscr, hr, 2, when, rw, SEL_DIM if is_sel else DIM)
+        safe_add(stdscr, hr, 14, f"{dur:>6}", rw, SEL_YELLOW if is_sel else YELLOW)
+        safe_add(stdscr, hr, 21, agents, rw, SEL_MAGENTA if is_sel else MAGENTA)
+        if tag:
+            safe_add(stdscr, hr, 26, tag, rw, SEL_CYAN if is_sel else CYAN)
+        safe_add(stdscr, hr, 26 + (len(tag) + 1 if tag else 0), prompt, rw, SEL if is_sel else WHITE)
+    if len(rows) > n_rows:  # on the filter line: the border is redrawn when the panel is highlighted
+        pos = f"{top + 1}-{min(top + n_rows, len(rows))}/{len(rows)}"
+        safe_add(stdscr, y + 1, w - len(pos) - 2, pos, rw, DIM)
+    return title
 
 
 def draw(stdscr, frame: int, state: dict, cache: dict):
@@ -1967,6 +3175,9 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
     c_agents = cache["c_agents"]
     recent = cache["recent"]
     tool_events = cache["tool_events"]
+    session_conflicts = cache.get("session_conflicts", {})
+    stalled_sessions = cache["data"].get("stalled_sessions", {})
+    stalled_agents = cache["data"].get("stalled_agents", {})
     activity = cache["activity"]
     team_data = cache["team_data"]
     session_lookup = cache["session_lookup"]
@@ -1996,6 +3207,9 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
     top_agents = cache["data"].get("top_agents", [])
     top_tools = cache["data"].get("top_tools", [])
     error_stats = cache["data"].get("error_stats", [])
+    top_files = cache["data"].get("top_files", [])
+    spend_stats = cache["data"].get("spend_stats", [])
+    state_daily = cache["data"].get("state_daily", [])
 
     # Helpers: clip to panel widths (preserve box borders)
     def L(r, c, text, attr=0):
@@ -2052,29 +3266,94 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
     teams_h = (2 + n_team_members + n_active_task_rows + n_team_subheaders) if teams else 0
 
     # Sessions panel: each session gets 1 row + 1 inline tool row if it has recent tools
+    # + 1 row listing contested files if it is in an edit conflict
     n_sess_rows = 0
     for s in active:
         n_sess_rows += 1
         if s["session_id"] in tool_events:
             n_sess_rows += 1  # inline tool line
+        if s["session_id"] in session_conflicts:
+            n_sess_rows += 1  # conflict line
     n_sess_rows += len(orphan_agents)
     sess_h = (2 + n_sess_rows) if (active or orphan_agents) else 0
 
+    # Attention panel: sessions blocked on the user, longest wait first
+    attention = [a for a in cache["data"].get("attention", []) if a["session_id"] in session_lookup]
+    attn_h = (2 + min(len(attention), MAX_ATTENTION)) if attention else 0
+
     # History + Stats share remaining left-panel space
     max_hist = 8
-    remaining = max(6, h - content_top - teams_h - sess_h - 1)
+    remaining = max(6, h - content_top - attn_h - teams_h - sess_h - 1)
     hist_h = min(2 + max_hist, remaining // 2 + remaining % 2)
     stats_lh = remaining - hist_h  # left-side stats height
 
     # If nothing active, show a small sessions box with idle message
     if not teams and not active and not r_agents:
         sess_h = 3
-        remaining = max(6, h - content_top - sess_h - 1)
+        remaining = max(6, h - content_top - attn_h - sess_h - 1)
         hist_h = min(2 + max_hist, remaining // 2 + remaining % 2)
         stats_lh = remaining - hist_h
 
-    # -- TEAMS panel --
+    # The HISTORY browser takes the space of both history and stats
+    if state.get("history_mode"):
+        hist_h += stats_lh
+        stats_lh = 0
+
+    # -- ATTENTION panel --
     cr = content_top
+    if attention:
+        wait_totals = cache["data"].get("wait_totals", {})
+        attn_title = f"ATTENTION  {len(attention)} waiting on you"
+        attn_first_idx = len(visible_items)
+        draw_box(stdscr, cr, 0, attn_h, lw, title=attn_title, border_attr=YELLOW)
+        ar = cr + 1
+        for wait in attention[:MAX_ATTENTION]:
+            sid = wait["session_id"]
+            sess = session_lookup[sid]
+            item = {
+                "agent_id": sid,
+                "agent_type": "session",
+                "session_id": sid,
+                "started_at": sess["created_at"],
+                "cwd": sess.get("cwd", ""),
+                "is_session": True,
+                "prompt": sess.get("prompt", ""),
+                "location": sess.get("location", {}),
+                **{k: sess.get(k) for k in SESSION_CONTEXT_KEYS + SESSION_STATE_KEYS},
+            }
+            vidx = len(visible_items)
+            visible_items.append(item)
+            is_sel = (vidx == state.get("selected", -1))
+            waited = fmt_dur(wait["started_at"])
+            long_wait = (datetime.now(timezone.utc) - (parse_dt(wait["started_at"]) or datetime.now(timezone.utc))).total_seconds() >= 300
+            icon = {"permission": "!", "input": "\u203a"}.get(wait.get("kind"), "?")
+            tag = dir_tag(sess.get("cwd", ""))
+            message = " ".join((wait.get("message") or wait.get("kind") or "").split())
+            text = f"{tag} {message}" if tag else message
+            text = text[:max(10, lw - 32)]
+            total = wait_totals.get(sid)
+            total_text = f"\u03a3 {fmt_dur_seconds(total)}" if total else ""
+            if is_sel:
+                try:
+                    stdscr.addnstr(ar, 1, " " * (lw - 2), lw - 2, SEL_DIM)
+                except curses.error:
+                    pass
+                L(ar, 2, "\u25b6", SEL_YELLOW)
+                L(ar, 4, f"{waited:>6}", SEL_RED if long_wait else SEL_YELLOW)
+                L(ar, 11, short_session(sid), SEL_DIM)
+                L(ar, 18, text, SEL)
+                L(ar, lw - 12, total_text, SEL_DIM)
+            else:
+                L(ar, 2, icon, YELLOW if wait.get("kind") == "permission" else CYAN)
+                L(ar, 4, f"{waited:>6}", RED if long_wait else YELLOW)
+                L(ar, 11, short_session(sid), DIM)
+                L(ar, 18, text, WHITE)
+                L(ar, lw - 12, total_text, DIM)
+            ar += 1
+        panel_ranges.append((cr, attn_h, attn_first_idx, len(visible_items) - 1, attn_title))
+        cr += attn_h
+
+    # -- TEAMS panel --
     if teams:
         # Build title
         if len(teams) == 1:
@@ -2135,6 +3414,7 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
                     "cwd": sess.get("cwd", ""),
                     "team_name": team["name"],
                     "teammate_name": teammate_name,
+                    "location": sess.get("location", {}),
                     "is_teammate": True,
                     "_tasks": team["tasks"],
                 }
@@ -2190,14 +3470,14 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
                         L(tr, base + 7, task_text, YELLOW)
                     tr += 1
 
-        panel_ranges.append((content_top, teams_h, teams_first_idx, len(visible_items) - 1, box_title))
+        panel_ranges.append((cr, teams_h, teams_first_idx, len(visible_items) - 1, box_title))
         cr += teams_h
 
     # -- SESSIONS panel --
     if active or orphan_agents:
         n_running = sum(
             1 for s in active
-            if not s.get("lastWaitUserAt") or s["session_id"] in agents_by_session
+            if not session_waiting(s) or s["session_id"] in agents_by_session
         )
         n_waiting = len(active) - n_running
         sess_counts = []
@@ -2207,6 +3487,10 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
             sess_counts.append(f"{n_waiting} waiting")
         if r_agents:
             sess_counts.append(f"{len(r_agents)} agent{'s' if len(r_agents) != 1 else ''}")
+        n_stalled = sum(1 for s in active if s["session_id"] in stalled_sessions
+                        or any(a["agent_id"] in stalled_agents for a in agents_by_session.get(s["session_id"], [])))
+        if n_stalled:
+            sess_counts.append(f"{n_stalled} stalled")
         sess_title = f"SESSIONS  {' \u00b7 '.join(sess_counts)}" if sess_counts else "SESSIONS"
 
         sess_first_idx = len(visible_items)
@@ -2218,10 +3502,20 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
                 break
             sid = s["session_id"]
             has_agents = sid in agents_by_session
-            waiting = bool(s.get("lastWaitUserAt")) and not has_agents
+            waiting = session_waiting(s) and not has_agents
             sid_short = short_session(sid)
-            prompt = short_prompt(s.get("prompt"), max(10, lw - 32))
+            contested = session_conflicts.get(sid, [])
+            st_text, st_attr = state_label(s.get("state"))
+            prompt = short_prompt(s.get("prompt"), max(10, lw - (42 if contested else 38)))
             spark = sparkline(activity.get(sid, []))
+            # Watchdog: the session, or some of its agents, has gone quiet — shown instead of the sparkline
+            n_stalled = sum(1 for a in agents_by_session.get(sid, []) if a["agent_id"] in stalled_agents)
+            if sid in stalled_sessions:
+                stall = f"\u29d7 {fmt_dur_seconds(stalled_sessions[sid] or 0)}"
+            elif n_stalled:
+                stall = f"\u29d7 {n_stalled} agent{'s' if n_stalled != 1 else ''}"
+            else:
+                stall = ""
 
             # Make session selectable
             sess_item = {
@@ -2232,6 +3526,8 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
                 "cwd": s.get("cwd", ""),
                 "is_session": True,
                 "prompt": s.get("prompt", ""),
+                "location": s.get("location", {}),
+                **{k: s.get(k) for k in SESSION_CONTEXT_KEYS + SESSION_STATE_KEYS},
             }
             vidx = len(visible_items)
             visible_items.append(sess_item)
@@ -2246,22 +3542,27 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
                 run_dur = fmt_dur(s["created_at"])
                 L(sr, 4, f"{run_dur:>6}", SEL_YELLOW)
                 L(sr, 11, sid_short, SEL_DIM)
-                L(sr, 18, prompt, SEL)
-                L(sr, lw - 12, spark, SEL_DIM)
+                L(sr, 18, st_text, SEL)
+                L(sr, 24, prompt, SEL)
+                L(sr, lw - 12, stall or spark, SEL_RED if stall else SEL_DIM)
             elif waiting:
                 # Frozen clock: duration from start to when it stopped working
-                frozen_dur = fmt_dur(s["created_at"], s.get("lastWaitUserAt"))
+                frozen_dur = fmt_dur(s["created_at"], s.get("lastWaitUserAt") or s.get("state_since"))
                 L(sr, 2, "\u25a1", DIM)  # □ paused
                 L(sr, 4, f"{frozen_dur:>6}", DIM)
                 L(sr, 11, sid_short, DIM)
-                L(sr, 18, prompt, DIM)
+                L(sr, 18, st_text, st_attr)
+                L(sr, 24, prompt, DIM)
             else:
                 run_dur = fmt_dur(s["created_at"])
                 L(sr, 2, pulse, GREEN)
-                L(sr, 4, f"{run_dur:>6}", YELLOW)
+                L(sr, 4, f"{run_dur:>6}", RED if stall else YELLOW)
                 L(sr, 11, sid_short, DIM)
-                L(sr, 18, prompt, WHITE)
-                L(sr, lw - 12, spark, CYAN)
+                L(sr, 18, st_text, st_attr)
+                L(sr, 24, prompt, WHITE)
+                L(sr, lw - 12, stall or spark, RED if stall else CYAN)
+            if contested:
+                L(sr, lw - 16, f"\u26a0{len(contested)}", SEL_RED if is_sel else RED)
             sr += 1
 
             # Inline tool line (most recent tool call for this session)
@@ -2281,6 +3582,18 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
                     L(sr, 6, tool_line, DIM)
                 sr += 1
 
+            # Files this session and another live session or agent are both editing
+            if contested and sr < cr + sess_h - 1:
+                conflict_line = ", ".join(os.path.basename(p) for p in contested)[:lw - 8]
+                if is_sel:
+                    try:
+                        stdscr.addnstr(sr, 1, " " * (lw - 2), lw - 2, SEL_DIM)
+                    except curses.error:
+                        pass
+                L(sr, 4, "\u26a0", SEL_RED if is_sel else RED)
+                L(sr, 6, conflict_line, SEL_RED if is_sel else RED)
+                sr += 1
+
         # Orphan agents
         for a in orphan_agents:
             if sr >= cr + sess_h - 1:
@@ -2336,7 +3649,16 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
     show_inline_detail = sel_agent is not None and not split
 
     hist_first_idx = len(visible_items)
-    if show_inline_detail:
+    hist_title, hist_box_h = "HISTORY", hist_h
+    if state.get("history_mode"):
+        # Narrow terminals keep the lower half for the selection's DETAIL
+        hist_box_h = hist_h if split else hist_h // 2
+        hist_title = _draw_history_browser(stdscr, cr, hist_box_h, lw, state, cache, visible_items)
+        if not split:
+            sel_agent = visible_items[sel_idx] if 0 <= sel_idx < len(visible_items) else None
+            draw_box(stdscr, cr + hist_box_h, 0, hist_h - hist_box_h, lw, title="DETAIL")
+            _draw_detail(stdscr, cr + hist_box_h + 1, 2, cr + hist_h - 1, lw - 1, sel_agent, cache)
+    elif show_inline_detail:
         draw_box(stdscr, cr, 0, hist_h, lw, title="DETAIL")
         _draw_detail(stdscr, cr + 1, 2, cr + hist_h - 1, lw - 1,
                      sel_agent, cache)
@@ -2348,7 +3670,7 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
         for a in c_agents:
             history.append(("agent", a["stopped_at"], a))
         for s in recent:
-            history.append(("prompt", s["stoped_at"], s))
+            history.append(("prompt", s["stopped_at"], s))
         history.sort(key=lambda x: x[1] or "", reverse=True)
 
         if not history:
@@ -2374,7 +3696,7 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
                         "transcript_path": item.get("transcript_path", ""),
                     }
                 elif kind == "prompt":
-                    dur = fmt_dur(item["created_at"], item["stoped_at"])
+                    dur = fmt_dur(item["created_at"], item["stopped_at"])
                     prompt = short_prompt(item.get("prompt"), max(10, lw - 21))
                     if not prompt:
                         continue
@@ -2388,6 +3710,7 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
                         "is_session": True,
                         "kind": "prompt",
                         "prompt": item.get("prompt", ""),
+                        "stopped_at": item.get("stopped_at", ""),
                     }
                 else:
                     continue
@@ -2426,7 +3749,7 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
                 shown += 1
 
     if len(visible_items) > hist_first_idx:
-        panel_ranges.append((cr, hist_h, hist_first_idx, len(visible_items) - 1, "HISTORY"))
+        panel_ranges.append((cr, hist_box_h, hist_first_idx, len(visible_items) - 1, hist_title))
 
     # -- STATS panel (bottom-left, below history) --
     cr_stats = cr + hist_h
@@ -2436,6 +3759,9 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
             f"[{r[0]}]" if i == state.get("stats_range", 2) else r[0]
             for i, r in enumerate(STATS_RANGES)
         )
+        stats_group = cache["data"].get("_stats_group_idx", 0)
+        if stats_group:
+            range_tabs += f"  by {STATS_GROUPS[stats_group][0]}"
         stats_first_idx = len(visible_items)
         stats_hl_active = state.get("focus") == "left" and state.get("selected", -1) < 0
         draw_box(stdscr, cr_stats, 0, stats_lh, lw, title=f"STATS  {range_tabs}",
@@ -2466,24 +3792,81 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
                     sr += 1
                 sr += 1
 
+        # Spend by project + model
+        if spend_stats and sr < max_sr - 1:
+            total_cost = sum(e["cost"] or 0 for e in spend_stats)
+            total_tok = sum(e["tokens"] or 0 for e in spend_stats)
+            L(sr, 2, f"SPEND   {range_label}  {fmt_cost(total_cost)} \u00b7 {fmt_tokens(total_tok)} tok", CYAN)
+            sr += 1
+            shown = spend_stats[:6]
+            # Models without a price still get a bar, by tokens
+            key = "cost" if any(e["cost"] for e in shown) else "tokens"
+            max_s = max(e[key] or 0 for e in shown)
+            stag_w = max((len(stat_tag(e, stats_group)) for e in shown), default=0)
+            model_w = max((len(short_model(e["model"])) for e in shown), default=0)
+            for entry in shown:
+                if sr >= max_sr:
+                    break
+                tag = stat_tag(entry, stats_group)
+                model = short_model(entry["model"])
+                cost = fmt_cost(entry["cost"] or 0)
+                bar = _bar(entry[key] or 0, max_s)
+                col1 = tag.ljust(stag_w)
+                col2 = model.ljust(model_w)
+                stat_item = {"agent_id": model, "agent_type": model, "session_id": "",
+                             "started_at": "", **stat_scope(entry, stats_group), "is_stat": True,
+                             "stat_kind": "spend", "stat_count": cost, "stat_label": f"{tag} {model}",
+                             "model": entry["model"]}
+                vidx = len(visible_items)
+                visible_items.append(stat_item)
+                line = f"{col1}  {col2}  {bar} {cost}  {fmt_tokens(entry['tokens'] or 0)}"
+                if vidx == state.get("selected", -1):
+                    try:
+                        stdscr.addnstr(sr, 1, " " * (lw - 2), lw - 2, SEL_DIM)
+                    except curses.error:
+                        pass
+                    L(sr, 2, line, SEL)
+                else:
+                    L(sr, 2, line, DIM)
+                sr += 1
+            sr += 1
+
+        # Time working vs blocked on the user, per day: █ working, ▓ blocked
+        if any(d["working"] or d["blocked"] for d in state_daily) and sr < max_sr - 1:
+            total_w = sum(d["working"] or 0 for d in state_daily)
+            total_b = sum(d["blocked"] or 0 for d in state_daily)
+            L(sr, 2, f"TIME    {range_label}  working {fmt_dur_seconds(total_w)} \u00b7 blocked on you {fmt_dur_seconds(total_b)}", CYAN)
+            sr += 1
+            max_t = max((d["working"] or 0) + (d["blocked"] or 0) for d in state_daily)
+            for d in state_daily:
+                if sr >= max_sr:
+                    break
+                w_cells = int((d["working"] or 0) / max_t * 12) if max_t > 0 else 0
+                b_cells = int((d["blocked"] or 0) / max_t * 12) if max_t > 0 else 0
+                bar = "\u2588" * w_cells + "\u2593" * b_cells + "\u2591" * (12 - w_cells - b_cells)
+                L(sr, 2, f"{d['day'][5:]}  {bar}  {fmt_dur_seconds(d['working'] or 0):>6} working  "
+                         f"{fmt_dur_seconds(d['blocked'] or 0):>6} blocked", DIM)
+                sr += 1
+            sr += 1
+
         # Agent rankings
         if top_agents and sr < max_sr - 1:
             L(sr, 2, f"AGENTS  {range_label}", CYAN)
             sr += 1
             max_a = top_agents[0]["cnt"]
-            tag_w = max((len(dir_tag(e.get("cwd", ""))) for e in top_agents), default=0)
+            tag_w = max((len(stat_tag(e, stats_group)) for e in top_agents), default=0)
             type_w = max((len(e["agent_type"] or "?") for e in top_agents), default=0)
             for entry in top_agents:
                 if sr >= max_sr:
                     break
-                tag = dir_tag(entry.get("cwd", ""))
+                tag = stat_tag(entry, stats_group)
                 atype = entry["agent_type"] or "?"
                 cnt = entry["cnt"]
                 bar = _bar(cnt, max_a)
                 col1 = tag.ljust(tag_w)
                 col2 = atype.ljust(type_w)
                 stat_item = {"agent_id": atype, "agent_type": atype, "session_id": "",
-                             "started_at": "", "cwd": entry.get("cwd", ""), "is_stat": True,
+                             "started_at": "", **stat_scope(entry, stats_group), "is_stat": True,
                              "stat_kind": "agent", "stat_count": cnt, "stat_label": f"{tag} {atype}"}
                 vidx = len(visible_items)
                 visible_items.append(stat_item)
@@ -2504,19 +3887,19 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
             L(sr, 2, f"TOOLS   {range_label}", CYAN)
             sr += 1
             max_t = top_tools[0]["cnt"]
-            ttag_w = max((len(dir_tag(e.get("cwd", ""))) for e in top_tools), default=0)
+            ttag_w = max((len(stat_tag(e, stats_group)) for e in top_tools), default=0)
             tname_w = max((len(e["tool_name"] or "?") for e in top_tools), default=0)
             for entry in top_tools:
                 if sr >= max_sr:
                     break
-                tag = dir_tag(entry.get("cwd", ""))
+                tag = stat_tag(entry, stats_group)
                 tname = entry["tool_name"] or "?"
                 cnt = entry["cnt"]
                 bar = _bar(cnt, max_t)
                 col1 = tag.ljust(ttag_w)
                 col2 = tname.ljust(tname_w)
                 stat_item = {"agent_id": tname, "agent_type": tname, "session_id": "",
-                             "started_at": "", "cwd": entry.get("cwd", ""), "is_stat": True,
+                             "started_at": "", **stat_scope(entry, stats_group), "is_stat": True,
                              "stat_kind": "tool", "stat_count": cnt, "stat_label": f"{tag} {tname}"}
                 vidx = len(visible_items)
                 visible_items.append(stat_item)
@@ -2531,25 +3914,57 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
                     L(sr, 2, f"{col1}  {col2}  {bar} {cnt}", DIM)
                 sr += 1
 
+        # Most-edited files
+        if top_files and sr < max_sr - 1:
+            sr += 1  # blank line before section
+            L(sr, 2, f"FILES   {range_label}  most edited", CYAN)
+            sr += 1
+            max_f = top_files[0]["cnt"]
+            path_w = max(10, lw - 22)
+            path_w = min(path_w, max(len(short_path(e["path"], maxlen=path_w)) for e in top_files))
+            for entry in top_files:
+                if sr >= max_sr:
+                    break
+                path = entry["path"]
+                cnt = entry["cnt"]
+                bar = _bar(cnt, max_f)
+                shown_path = short_path(path, maxlen=path_w)
+                stat_item = {"agent_id": path, "agent_type": path, "session_id": "",
+                             "started_at": "", "cwd": "", "is_stat": True,
+                             "stat_kind": "file", "stat_count": cnt, "stat_label": shown_path}
+                vidx = len(visible_items)
+                visible_items.append(stat_item)
+                sessions = f" \u00b7{entry['sessions']}s" if entry["sessions"] > 1 else ""
+                line = f"{shown_path.ljust(path_w)}  {bar} {cnt}{sessions}"
+                if vidx == state.get("selected", -1):
+                    try:
+                        stdscr.addnstr(sr, 1, " " * (lw - 2), lw - 2, SEL_DIM)
+                    except curses.error:
+                        pass
+                    L(sr, 2, line, SEL)
+                else:
+                    L(sr, 2, line, DIM)
+                sr += 1
+
         # Error rankings
         if error_stats and sr < max_sr - 1:
             sr += 1  # blank line before section
             L(sr, 2, f"ERRORS  {range_label}", RED)
             sr += 1
             max_e = error_stats[0]["cnt"]
-            etag_w = max((len(dir_tag(e.get("cwd", ""))) for e in error_stats), default=0)
+            etag_w = max((len(stat_tag(e, stats_group)) for e in error_stats), default=0)
             ename_w = max((len(e["tool_name"] or "?") for e in error_stats), default=0)
             for entry in error_stats:
                 if sr >= max_sr:
                     break
-                tag = dir_tag(entry.get("cwd", ""))
+                tag = stat_tag(entry, stats_group)
                 tname = entry["tool_name"] or "?"
                 cnt = entry["cnt"]
                 bar = _bar(cnt, max_e)
                 col1 = tag.ljust(etag_w)
                 col2 = tname.ljust(ename_w)
                 stat_item = {"agent_id": tname, "agent_type": tname, "session_id": "",
-                             "started_at": "", "cwd": entry.get("cwd", ""), "is_stat": True,
+                             "started_at": "", **stat_scope(entry, stats_group), "is_stat": True,
                              "stat_kind": "error", "stat_count": cnt, "stat_label": f"{tag} {tname}"}
                 vidx = len(visible_items)
                 visible_items.append(stat_item)
@@ -2577,6 +3992,10 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
     sel_idx = state.get("selected", -1)
     sel_agent = visible_items[sel_idx] if 0 <= sel_idx < len(visible_items) else None
 
+    # A session that is no longer active (HISTORY browser or panel) is read back from the database
+    if sel_agent and sel_agent.get("session_id") and sel_agent["session_id"] not in session_lookup:
+        open_session(cache, sel_agent["session_id"])
+
     # Highlight the left panel that contains the selected item
     if state.get("focus") == "left" and sel_idx >= 0:
         for py, ph, fi, li, ptitle in panel_ranges:
@@ -2590,11 +4009,20 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
         focused = state.get("focus") == "right"
         viz_mode = VIZ_MODES[state.get("viz_mode", 0) % len(VIZ_MODES)] if state.get("viz_mode", 0) < len(VIZ_MODES) else "life"
 
+        if viz_mode != "notifications":
+            state.pop("_notif_seen", None)
+
         # Build title with tab selector
-        tabs = "  ".join(f"[{VIZ_LABELS[m]}]" if m == viz_mode else VIZ_LABELS[m] for m in VIZ_MODES)
+        unread = unread_count(cache["data"].get("notifications", []), state.get("notif_read", 0))
+        labels = {m: VIZ_LABELS[m] for m in VIZ_MODES}
+        if unread:
+            labels["notifications"] += f" {unread}"
+        tabs = "  ".join(f"[{labels[m]}]" if m == viz_mode else labels[m] for m in VIZ_MODES)
         if state.get("game_of_life"):
             tabs += "  LIFE"
         title_prefix = "\u25b6 " if focused else ""
+        if state.get("diff_view"):
+            tabs = f"DIFF  {state['diff_view']['title']}"[:max(0, rw - 8)]
         draw_box(stdscr, content_top, rx, total_rh, rw,
                  title=f"{title_prefix}{tabs}",
                  border_attr=CYAN if focused else 0)
@@ -2602,27 +4030,54 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
         panel_y = content_top + 1
         panel_h = total_rh - 2
 
-        if viz_mode == "tree":
+        if state.get("diff_view"):
+            _draw_diff_view(stdscr, panel_y, rx, panel_h, rw, state)
+
+        elif viz_mode == "tree":
             # Merged: show DETAIL header + tree for selected item
             if sel_agent:
                 pr = panel_y
                 rw_abs = rx + rw - 1
                 # Header
                 sid_short = sel_agent["agent_id"][:7]
-                dur = fmt_dur(sel_agent.get("started_at", ""))
+                dur = fmt_dur(sel_agent.get("started_at", ""), sel_agent.get("stopped_at"))
                 tag = dir_tag(sel_agent.get("cwd", ""))
+                usage = usage_label(cache["data"].get("session_usage", {}).get(sel_agent.get("session_id", "")))
                 if sel_agent.get("is_session"):
                     header = f"{sid_short} \u00b7 {tag} \u00b7 {dur}" if tag else f"{sid_short} \u00b7 {dur}"
+                    if usage:
+                        header += f" \u00b7 {usage}"
                     safe_add(stdscr, pr, rx + 2, header[:rw - 4], rw_abs, GREEN | curses.A_BOLD)
+                    ctx_text = context_line(sel_agent)
+                    if ctx_text:
+                        pr += 1
+                        safe_add(stdscr, pr, rx + 2, ctx_text[:rw - 4], rw_abs, DIM)
+                    st_text = state_line(sel_agent)
+                    if st_text:
+                        pr += 1
+                        safe_add(stdscr, pr, rx + 2, st_text[:rw - 4], rw_abs, state_label(sel_agent.get("state"))[1])
+                    loc_text = location_line(sel_agent.get("location"))
+                    if loc_text:
+                        pr += 1
+                        safe_add(stdscr, pr, rx + 2, loc_text[:rw - 4], rw_abs, DIM)
                 elif sel_agent.get("is_teammate"):
                     tname = sel_agent.get("teammate_name", "")
                     header = f"{sid_short} \u00b7 {tname} \u00b7 {dur}"
+                    if usage:
+                        header += f" \u00b7 {usage}"
                     safe_add(stdscr, pr, rx + 2, header[:rw - 4], rw_abs, CYAN | curses.A_BOLD)
+                    loc_text = location_line(sel_agent.get("location"))
+                    if loc_text:
+                        pr += 1
+                        safe_add(stdscr, pr, rx + 2, loc_text[:rw - 4], rw_abs, DIM)
                 elif sel_agent.get("is_stat"):
                     safe_add(stdscr, pr, rx + 2, sel_agent.get("stat_label", "")[:rw - 4], rw_abs, CYAN | curses.A_BOLD)
                 else:
                     atype = sel_agent.get("agent_type") or "agent"
                     header = f"{sid_short} \u00b7 {tag} {atype} \u00b7 {dur}" if tag else f"{sid_short} \u00b7 {atype} \u00b7 {dur}"
+                    agent_usage = usage_label(cache["data"].get("agent_usage", {}).get(sel_agent["agent_id"]))
+                    if agent_usage:
+                        header += f" \u00b7 {agent_usage}"
                     safe_add(stdscr, pr, rx + 2, header[:rw - 4], rw_abs, MAGENTA | curses.A_BOLD)
                 pr += 1
                 safe_add(stdscr, pr, rx + 2, SYMBOLS["h"] * (rw - 4), rw_abs, DIM)
@@ -2635,6 +4090,15 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
         elif viz_mode == "gantt":
             _draw_viz_gantt(stdscr, panel_y, rx, panel_h, rw, cache, state)
 
+        elif viz_mode == "notifications":
+            _draw_viz_notifications(stdscr, panel_y, rx, panel_h, rw, cache, state)
+
+        elif viz_mode == "files":
+            _draw_viz_files(stdscr, panel_y, rx, panel_h, rw, cache, state)
+
+        elif viz_mode == "changes":
+            _draw_viz_changes(stdscr, panel_y, rx, panel_h, rw, cache, state)
+
         elif state.get("game_of_life") and state.get("viz_mode", 0) >= len(VIZ_MODES):
             # Game of Life
             life_sid = None
@@ -2694,13 +4158,17 @@ def draw(stdscr, frame: int, state: dict, cache: dict):
     status = state.get("status_msg", "")
     if status and time.time() < state.get("status_until", 0):
         safe_add(stdscr, h - 1, 0, f" {status}", w, YELLOW)
+    elif state.get("diff_view"):
+        safe_add(stdscr, h - 1, 0, " j/k=scroll  space/b=page  g/G=top/bottom  esc=close  q=quit", w, DIM)
+    elif state.get("history_mode") and state.get("focus") != "right":
+        safe_add(stdscr, h - 1, 0, " j/k=select  l/enter=open  p=project  t=date  u=length  n=agents  H=live  tab=viz  q=quit", w, DIM)
     elif visible_items:
         if state.get("focus") == "right":
             safe_add(stdscr, h - 1, 0, " j/k=scroll  h=back  tab=viz  enter=open  q=quit", w, DIM)
         elif state.get("selected", -1) >= 0:
-            safe_add(stdscr, h - 1, 0, " j/k=select  l/enter=detail  h/l=stats  tab=viz  esc=deselect  q=quit", w, DIM)
+            safe_add(stdscr, h - 1, 0, " j/k=select  l/enter=detail  g=jump  a=next waiting  h/l=stats  tab=viz  esc=deselect  q=quit", w, DIM)
         else:
-            safe_add(stdscr, h - 1, 0, " j/k=select  h/l=stats range  tab=viz  q=quit", w, DIM)
+            safe_add(stdscr, h - 1, 0, " j/k=select  h/l=stats range  H=history  tab=viz  q=quit", w, DIM)
     else:
         safe_add(stdscr, h - 1, 0, " tab=viz  q=quit", w, DIM)
     stdscr.refresh()
@@ -2719,19 +4187,54 @@ def main(stdscr, game_of_life=False):
     init_colors()
 
     state: dict = {"selected": 0, "visible_items": [], "status_msg": "", "status_until": 0.0,
-                   "stats_range": 2, "game_of_life": game_of_life, "focus": "left", "detail_scroll": 0,
-                   "viz_mode": 0, "tree_filter": 0}
+                   "stats_range": 2, "stats_group": 0, "game_of_life": game_of_life, "focus": "left", "detail_scroll": 0,
+                   "viz_mode": 0, "tree_filter": 0, "notif_read": load_read_marker()}
     cache: dict = {}
-    refresh_data(cache, state["stats_range"])
+    refresh_data(cache, state["stats_range"], state["stats_group"])
     frame = 0
     while True:
         if frame % DATA_FRAMES == 0:
-            refresh_data(cache, state["stats_range"])
+            refresh_data(cache, state["stats_range"], state["stats_group"])
         draw(stdscr, frame, state, cache)
         frame += 1
         ch = stdscr.getch()
         if ch in (ord("q"), ord("Q")):
             break
+        elif state.get("diff_view") and ch != -1:  # diff viewer has the keyboard until closed
+            dv = state["diff_view"]
+            page = state.get("_diff_page", 10)
+            if ch in (ord("j"), curses.KEY_DOWN):
+                dv["scroll"] += 1
+            elif ch in (ord("k"), curses.KEY_UP):
+                dv["scroll"] = max(0, dv["scroll"] - 1)
+            elif ch in (32, curses.KEY_NPAGE):
+                dv["scroll"] += page
+            elif ch in (ord("b"), curses.KEY_PPAGE):
+                dv["scroll"] = max(0, dv["scroll"] - page)
+            elif ch == ord("g"):
+                dv["scroll"] = 0
+            elif ch == ord("G"):
+                dv["scroll"] = len(dv["lines"])
+            elif ch in (27, ord("d"), ord("h"), curses.KEY_LEFT):
+                if ch == 27:
+                    stdscr.nodelay(True)
+                    if stdscr.getch() != -1:  # an escape sequence, not Esc itself
+                        stdscr.timeout(RENDER_MS)
+                        continue
+                    stdscr.timeout(RENDER_MS)
+                state.pop("diff_view", None)
+        elif ch == ord("d") and state["focus"] == "right":  # open the highlighted tool call's diff
+            tl = state.get("_tree_timeline", [])
+            tc = state.get("tree_cursor", 0)
+            if state.get("viz_mode", 0) == VIZ_MODES.index("tree") and 0 <= tc < len(tl) and tl[tc].get("_diff_path"):
+                ev = tl[tc]
+                vis = state["visible_items"]
+                sel = state["selected"]
+                sid = vis[sel].get("session_id", "") if 0 <= sel < len(vis) else ""
+                if not sid and cache.get("active_all"):
+                    sid = cache["active_all"][0]["session_id"]
+                open_diff(state, f"{short_path(ev['_diff_path'])}  {ev.get('_tool_name', '')}", sid,
+                          tool_use_id=ev.get("_tool_use_id", ""))
         elif ch == 9:  # Tab — cycle viz mode forward, auto-focus right panel
             n_modes = len(VIZ_MODES) + (1 if state.get("game_of_life") else 0)
             state["viz_mode"] = (state["viz_mode"] + 1) % n_modes
@@ -2757,6 +4260,49 @@ def main(stdscr, game_of_life=False):
                     state["selected"] = -1
             # else: was an escape sequence — ignore (arrow keys handled by KEY_UP etc.)
             stdscr.timeout(RENDER_MS)
+        elif ch == ord("g"):  # jump to the selected session's terminal pane
+            sel = state["selected"]
+            items = state["visible_items"]
+            if 0 <= sel < len(items) and not items[sel].get("is_stat"):
+                state["status_msg"] = jump_to_item(items[sel], cache)
+                state["status_until"] = time.time() + 3
+        elif ch == ord("a"):  # jump to the session that has waited longest on you; again for the next one
+            attention = [w for w in cache["data"].get("attention", []) if w["session_id"] in cache.get("session_lookup", {})]
+            if attention:
+                wait = attention[state.get("_attn_next", 0) % len(attention)]
+                state["_attn_next"] = state.get("_attn_next", 0) + 1
+                select_session(state, wait["session_id"])
+                state["status_msg"] = jump_to_item({"session_id": wait["session_id"]}, cache)
+            else:
+                state["status_msg"] = "nothing is waiting on you"
+            state["status_until"] = time.time() + 3
+        elif ch == ord("s"):  # cycle STATS grouping: project, branch, model, permission mode
+            state["stats_group"] = (state["stats_group"] + 1) % len(STATS_GROUPS)
+            refresh_data(cache, state["stats_range"], state["stats_group"])
+            state["status_msg"] = f"stats by {STATS_GROUPS[state['stats_group']][0]}"
+            state["status_until"] = time.time() + 2
+        elif ch == ord("H"):  # HISTORY browser: ended sessions in place of the history and stats panels
+            state["history_mode"] = not state.get("history_mode")
+            state["_hist_select_first"] = state["history_mode"]
+            state["selected"] = -1
+            state["focus"] = "left"
+            state["detail_scroll"] = 0; state["tree_cursor"] = 0
+            state["_expanded_tool"] = -1
+        elif state.get("history_mode") and ch in (ord("p"), ord("t"), ord("u"), ord("n")):  # HISTORY filters
+            if ch == ord("p"):  # next project in the list, then back to all
+                _, projects = history_sessions(cache, state)
+                cur = state.get("hist_project")
+                nxt = projects.index(cur) + 1 if cur in projects else 0
+                state["hist_project"] = projects[nxt] if nxt < len(projects) else None
+            elif ch == ord("t"):
+                state["hist_range"] = (state.get("hist_range", 2) + 1) % len(STATS_RANGES)
+            elif ch == ord("u"):
+                state["hist_dur"] = (state.get("hist_dur", 0) + 1) % len(HISTORY_DURATIONS)
+            else:
+                state["hist_agents"] = (state.get("hist_agents", 0) + 1) % len(HISTORY_AGENTS)
+            state["_hist_top"] = 0
+            state["_hist_select_first"] = True
+            state["focus"] = "left"
         elif ch in (ord("j"), curses.KEY_DOWN):
             if state["focus"] == "right":
                 viz_m = VIZ_MODES[state.get("viz_mode", 0) % len(VIZ_MODES)] if state.get("viz_mode", 0) < len(VIZ_MODES) else ""
@@ -2805,7 +4351,7 @@ def main(stdscr, game_of_life=False):
                     old = state["stats_range"]
                     state["stats_range"] = min(old + 1, len(STATS_RANGES) - 1)
                     if state["stats_range"] != old:
-                        refresh_data(cache, state["stats_range"])
+                        refresh_data(cache, state["stats_range"], state["stats_group"])
         elif ch in (ord("h"), curses.KEY_LEFT):
             viz_m = VIZ_MODES[state.get("viz_mode", 0) % len(VIZ_MODES)] if state.get("viz_mode", 0) < len(VIZ_MODES) else ""
             gn = state.get("graph_nodes", [])
@@ -2826,13 +4372,28 @@ def main(stdscr, game_of_life=False):
                 old = state["stats_range"]
                 state["stats_range"] = max(old - 1, 0)
                 if state["stats_range"] != old:
-                    refresh_data(cache, state["stats_range"])
+                    refresh_data(cache, state["stats_range"], state["stats_group"])
         elif ch in (32, 10, 13, curses.KEY_ENTER):  # Space or Enter
             if ch in (10, 13, curses.KEY_ENTER) and state["focus"] == "left" and state["selected"] >= 0:
                 # Enter from left focuses right panel
                 state["focus"] = "right"
                 state["detail_scroll"] = 0; state["tree_cursor"] = 0
                 state["_expanded_tool"] = -1
+            elif state.get("focus") == "right" and state.get("viz_mode", 0) == VIZ_MODES.index("notifications"):
+                # Jump from a notification to its session in the tree
+                nl = state.get("_notif_list", [])
+                tc = state.get("tree_cursor", 0)
+                if 0 <= tc < len(nl) and not select_session(state, nl[tc].get("session_id") or ""):
+                    state["status_msg"] = "session is no longer listed"
+                    state["status_until"] = time.time() + 3
+            elif state.get("focus") == "right" and state.get("viz_mode", 0) == VIZ_MODES.index("files"):
+                pass  # file rows have nothing to expand
+            elif state.get("focus") == "right" and state.get("viz_mode", 0) == VIZ_MODES.index("changes"):
+                sid, files = state.get("_changes", ("", []))
+                tc = state.get("tree_cursor", 0)
+                if 0 <= tc < len(files):
+                    open_diff(state, f"{short_path(files[tc]['path'])}  \u00d7{files[tc]['edits']}", sid,
+                              path=files[tc]["path"])
             elif state.get("focus") == "right":
                 tl = state.get("_tree_timeline", [])
                 tc = state.get("tree_cursor", 0)
@@ -2870,6 +4431,103 @@ def self_update():
     sys.exit(result.returncode)
 
 
+def installed_hook_schema(db_path: str = DB_PATH) -> int | None:
+    """SCHEMA_VERSION of the ccnotify.py writing to db_path, or None if there isn't one."""
+    hook = os.path.join(os.path.dirname(db_path), "ccnotify.py")
+    try:
+        with open(hook) as f:
+            m = re.search(r"^SCHEMA_VERSION = (\d+)", f.read(), re.M)
+    except OSError:
+        return None
+    # hooks from before versioning have no SCHEMA_VERSION at all
+    return int(m.group(1)) if m else 0
+
+
+def _hook_outdated(db_path: str) -> bool:
+    hook = installed_hook_schema(db_path)
+    return hook is not None and hook < _ccnotify.SCHEMA_VERSION
+
+
+def ensure_schema(db_path: str = DB_PATH):
+    """Migrate the database before the dashboard reads it.
+
+    The only write agent-top makes on startup, and only when needed. Refuses
+    (and exits) while the installed hook is older than this package — migrating
+    under it would leave the hook writing to columns that moved — or when the
+    database can't be written at all.
+    """
+    if not os.path.exists(db_path):
+        return
+    with connect_ro(db_path) as conn:
+        current = _ccnotify.schema_version(conn)
+    if current >= _ccnotify.SCHEMA_VERSION:
+        return
+    if _hook_outdated(db_path):
+        sys.exit(
+            f"agent-top: {os.path.dirname(db_path)}/ccnotify.py predates database schema "
+            f"v{_ccnotify.SCHEMA_VERSION}.\n"
+            "Run `agent-top --setup` to update it, then start agent-top again."
+        )
+    if not os.access(db_path, os.W_OK) or not os.access(os.path.dirname(os.path.abspath(db_path)), os.W_OK):
+        sys.exit(
+            f"agent-top: {db_path} is read-only at schema v{current} (needs v{_ccnotify.SCHEMA_VERSION}).\n"
+            "Migrate a writable copy with `AGENT_TOP_DB=<copy> agent-top db migrate`."
+        )
+    with sqlite3.connect(db_path) as conn:
+        _ccnotify.migrate(conn)
+
+
+def db_migrate(dry_run=False, force=False):
+    if not os.path.exists(DB_PATH):
+        print(f"  No database at {DB_PATH}")
+        return
+    with sqlite3.connect(DB_PATH) as conn:
+        current = _ccnotify.schema_version(conn)
+        print(f"  {DB_PATH}: schema v{current}, agent-top expects v{_ccnotify.SCHEMA_VERSION}")
+        if not dry_run and not force and _hook_outdated(DB_PATH):
+            print("  The installed ccnotify.py is older than this schema — run `agent-top --setup` first")
+            print("  (or pass --force to migrate anyway).")
+            sys.exit(1)
+        steps = _ccnotify.migrate(conn, dry_run=dry_run)
+    if not steps:
+        print("  Up to date.")
+        return
+    for version, desc, stmts in steps:
+        print(f"  {'would apply' if dry_run else 'applied'} v{version}: {desc}")
+        for sql in stmts:
+            print("      " + " ".join(sql.split()) + ";")
+        if not stmts:
+            print("      (nothing to change)")
+
+
+def db_reap():
+    """Close out dead sessions and orphaned agents now instead of waiting for maintenance."""
+    if not os.path.exists(DB_PATH):
+        print(f"  No database at {DB_PATH}")
+        return
+    with sqlite3.connect(DB_PATH) as conn:
+        reaped = _ccnotify.reap(conn)
+    print(f"  Closed {reaped['sessions']} prompt row(s) and {reaped['agents']} agent(s).")
+
+
+def db_rebuild(force=False):
+    """Regenerate sessions, agents, tool events and teams from the raw event log."""
+    if not os.path.exists(DB_PATH):
+        print(f"  No database at {DB_PATH}")
+        return
+    if not force and _hook_outdated(DB_PATH):
+        print("  The installed ccnotify.py is older than this version — run `agent-top --setup` first")
+        print("  (or pass --force to rebuild anyway).")
+        sys.exit(1)
+    result = _ccnotify.ClaudePromptTracker(DB_PATH, log=False).rebuild()
+    if not result["since"]:
+        print("  The event log is empty — nothing to rebuild.")
+        return
+    print(f"  Replayed {result['replayed']} event(s) logged since {result['since']} UTC.")
+    if result["failed"]:
+        print(f"  {result['failed']} event(s) could not be applied.")
+
+
 def setup(no_ccnotify=False):
     from pathlib import Path
     import shutil
@@ -2880,14 +4538,15 @@ def setup(no_ccnotify=False):
 
     if no_ccnotify:
         print("  Skipping ccnotify installation.")
-    elif dest.exists():
+    elif dest.exists() and not _hook_outdated(str(dest.with_name("ccnotify.db"))):
         print(f"  ccnotify.py already exists at {dest} — skipping (delete it first to reinstall)")
     else:
         dest.parent.mkdir(parents=True, exist_ok=True)
+        replacing = dest.exists()
         with importlib.resources.path("agent_top", "_ccnotify.py") as src:
             shutil.copy(src, dest)
         dest.chmod(dest.stat().st_mode | stat.S_IEXEC | stat.S_IXGRP | stat.S_IXOTH)
-        print(f"  ccnotify.py  →  {dest}")
+        print(f"  ccnotify.py  →  {dest}" + ("  (updated: older schema)" if replacing else ""))
 
     if not no_ccnotify:
         print()
@@ -2905,7 +4564,8 @@ def setup(no_ccnotify=False):
     "Notification":     [{{"matcher": "", "hooks": [{{"type": "command", "command": "{dest} Notification"}}]}}],
     "PreToolUse":       [{{"matcher": "", "hooks": [{{"type": "command", "command": "{dest} PreToolUse"}}]}}],
     "PostToolUse":      [{{"matcher": "", "hooks": [{{"type": "command", "command": "{dest} PostToolUse"}}]}}],
-    "PostToolUseFailure": [{{"matcher": "", "hooks": [{{"type": "command", "command": "{dest} PostToolUseFailure"}}]}}]
+    "PostToolUseFailure": [{{"matcher": "", "hooks": [{{"type": "command", "command": "{dest} PostToolUseFailure"}}]}}],
+    "PreCompact":       [{{"matcher": "", "hooks": [{{"type": "command", "command": "{dest} PreCompact"}}]}}]
   }}
 }}''')
     print()
@@ -2935,8 +4595,34 @@ def cli():
     parser.add_argument(
         "--game-of-life", action="store_true", help="show Conway's Game of Life"
     )
+    sub = parser.add_subparsers(dest="command", metavar="COMMAND")
+    db = sub.add_parser("db", help="database maintenance")
+    db_sub = db.add_subparsers(dest="db_command", metavar="ACTION", required=True)
+    mig = db_sub.add_parser("migrate", help="upgrade the database schema")
+    mig.add_argument(
+        "--dry-run", action="store_true", help="print the pending migrations without applying them"
+    )
+    mig.add_argument(
+        "--force", action="store_true", help="migrate even if the installed ccnotify.py is older"
+    )
+    reb = db_sub.add_parser("rebuild", help="regenerate sessions, agents and tool events from the event log")
+    reb.add_argument(
+        "--force", action="store_true", help="rebuild even if the installed ccnotify.py is older"
+    )
+    sub.add_parser("reap", help="close out sessions whose Claude process is gone, and their agents")
     args = parser.parse_args()
 
+    if args.command == "reap":
+        db_reap()
+        sys.exit(0)
+
+    if args.command == "db":
+        if args.db_command == "rebuild":
+            db_rebuild(force=args.force)
+        else:
+            db_migrate(dry_run=args.dry_run, force=args.force)
+        sys.exit(0)
+
     if args.setup:
         setup(no_ccnotify=getattr(args, "no_ccnotify", False))
         sys.exit(0)
@@ -2945,6 +4631,7 @@ def cli():
         self_update()
         sys.exit(0)
 
+    ensure_schema()
     try:
         curses.wrapper(lambda stdscr: main(stdscr, game_of_life=args.game_of_life))
     except KeyboardInterrupt:
@@ -2,21 +2,53 @@
 """
 Claude Code Notify — desktop notifications for Claude Code hooks.
 Consolidated handler for Stop, SubagentStart, SubagentStop, Notification,
-PreToolUse, and UserPromptSubmit.
+PreToolUse, PostToolUse, PostToolUseFailure, and UserPromptSubmit.
 """
 
+import difflib
+import fnmatch
+import gzip
 import json
 import logging
 import os
 import random
+import re
+import queue
+import shutil
+import signal
+import socket
 import sqlite3
 import subprocess
 import sys
-from datetime import datetime, timezone
+import threading
+import time
+from datetime import datetime, timedelta, timezone
 from logging.handlers import TimedRotatingFileHandler
 
 SCRIPT_DIR = os.path.dirname(os.path.abspath(__file__))
 SOUNDS_DIR = os.path.join(SCRIPT_DIR, "sounds")
+CONFIG_PATH = os.environ.get("CCNOTIFY_CONFIG") or os.path.join(SCRIPT_DIR, "config.json")
+RULES_PATH = os.environ.get("CCNOTIFY_RULES") or os.path.join(SCRIPT_DIR, "rules.json")
+
+_config: dict | None = None
+
+
+def load_config() -> dict:
+    """Read config.json next to this script (or $CCNOTIFY_CONFIG). Missing file = defaults."""
+    global _config
+    if _config is None:
+        _config = {}
+        try:
+            with open(CONFIG_PATH, "r", encoding="utf-8") as f:
+                obj = json.load(f)
+            if isinstance(obj, dict):
+                _config = obj
+        except FileNotFoundError:
+            pass
+        except Exception as e:
+            logging.error(f"Config error in {CONFIG_PATH}: {e}")
+    return _config
+
 
 # macOS system sounds — one per event type, simple and not annoying.
 SYSTEM_SOUNDS: dict[str, str] = {
@@ -27,19 +59,48 @@ SYSTEM_SOUNDS: dict[str, str] = {
     "error": "/System/Library/Sounds/Basso.aiff",
 }
 
+# freedesktop sound theme — shipped by most Linux desktops.
+FREEDESKTOP_SOUNDS: dict[str, str] = {
+    "task_complete": "/usr/share/sounds/freedesktop/stereo/complete.oga",
+    "subagent_complete": "/usr/share/sounds/freedesktop/stereo/message.oga",
+    "waiting_input": "/usr/share/sounds/freedesktop/stereo/bell.oga",
+    "permission": "/usr/share/sounds/freedesktop/stereo/dialog-warning.oga",
+    "error": "/usr/share/sounds/freedesktop/stereo/dialog-error.oga",
+}
+
+SOUND_EXTS = (".aiff", ".wav", ".oga", ".ogg", ".mp3")
+
+
 def _pick_sound(event_key: str) -> str | None:
-    """Return the system sound path for this event type."""
-    path = SYSTEM_SOUNDS.get(event_key, SYSTEM_SOUNDS["task_complete"])
+    """Return the sound file for this event type.
+
+    Lookup order: config "sounds" mapping, then sounds/<event_key>.<ext>
+    next to this script, then the platform's system sound.
+    """
+    custom = load_config().get("sounds", {})
+    if isinstance(custom, dict) and custom.get(event_key):
+        path = os.path.expanduser(custom[event_key])
+        if not os.path.isabs(path):
+            path = os.path.join(SOUNDS_DIR, path)
+        if os.path.exists(path):
+            return path
+    for ext in SOUND_EXTS:
+        path = os.path.join(SOUNDS_DIR, event_key + ext)
+        if os.path.exists(path):
+            return path
+    table = SYSTEM_SOUNDS if sys.platform == "darwin" else FREEDESKTOP_SOUNDS
+    path = table.get(event_key, table["task_complete"])
     return path if os.path.exists(path) else None
 
 
-def iterm_info() -> dict:
+def iterm_info(env=None) -> dict:
     """Get iTerm2 window/pane info for the session that fired the hook (not the focused one)."""
+    env = os.environ if env is None else env
     info = {"window": "", "window_num": 0, "window_total": 0,
             "pane_num": 0, "pane_total": 0, "pane_name": ""}
 
     # ITERM_SESSION_ID looks like "w0t0p5:C6684449-..."  — the UUID after ':' is the unique ID
-    iterm_session_id = os.environ.get("ITERM_SESSION_ID", "")
+    iterm_session_id = env.get("ITERM_SESSION_ID", "")
     target_id = iterm_session_id.split(":")[-1] if ":" in iterm_session_id else ""
     if not target_id:
         return info
@@ -100,65 +161,1797 @@ def _location_label(iterm: dict) -> str:
     return " · ".join(parts)
 
 
-def play_sound(sound_key: str) -> None:
-    """Play a Clash Royale sound effect, cycling through the list."""
-    path = _pick_sound(sound_key)
-    if path:
-        try:
-            subprocess.Popen(
-                ["afplay", "-v", "0.7", path],
-                stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL,
-            )
-            logging.info(f"Playing sound: {os.path.basename(path)} for {sound_key}")
-        except Exception:
-            pass
+# ── PANE LOCATION ────────────────────────────────────────────
+# Each provider inspects the hook's environment and returns a location dict:
+#   terminal  which provider matched ("tmux", "kitty", ...)
+#   window    short window/session name, used as the notification title
+#   label     human-readable "where is it" string
+#   ...plus whatever ids the provider needs to focus the pane again later.
+
+# Environment variables the providers read — forwarded verbatim when a hook
+# hands its payload to another process.
+LOCATION_ENV_KEYS = (
+    "TMUX", "TMUX_PANE", "ZELLIJ", "ZELLIJ_SESSION_NAME", "ZELLIJ_PANE_ID",
+    "WEZTERM_PANE", "WEZTERM_UNIX_SOCKET", "KITTY_WINDOW_ID", "KITTY_LISTEN_ON",
+    "ITERM_SESSION_ID", "TERM_PROGRAM",
+)
+
+
+def _run_quiet(cmd: list[str], timeout: float = 2) -> str | None:
+    """Run a lookup command, returning stdout or None on any failure."""
+    try:
+        r = subprocess.run(cmd, capture_output=True, text=True, timeout=timeout)
+        return r.stdout if r.returncode == 0 else None
+    except Exception:
+        return None
+
+
+def _tmux_location(env) -> dict | None:
+    pane = env.get("TMUX_PANE", "")
+    if not env.get("TMUX") or not pane:
+        return None
+    # TMUX is "<socket path>,<server pid>,<session idx>"
+    socket = env["TMUX"].split(",")[0]
+    loc = {"terminal": "tmux", "pane_id": pane, "socket": socket, "window": "", "label": f"tmux {pane}"}
+    out = _run_quiet(["tmux", "-S", socket, "display-message", "-p", "-t", pane,
+                      "#{session_name}\t#{window_index}\t#{window_name}\t#{pane_index}"])
+    if out:
+        parts = out.rstrip("\n").split("\t")
+        if len(parts) == 4:
+            sess, win_idx, win_name, pane_idx = parts
+            loc.update({"session": sess, "window_index": win_idx, "window": win_name,
+                        "label": f"tmux {sess}:{win_idx}.{pane_idx} · {win_name}"})
+    return loc
+
+
+def _zellij_location(env) -> dict | None:
+    if not env.get("ZELLIJ"):
+        return None
+    sess = env.get("ZELLIJ_SESSION_NAME", "")
+    pane = env.get("ZELLIJ_PANE_ID", "")
+    label = " · ".join(p for p in (f"zellij {sess}".strip(), f"pane {pane}" if pane else "") if p)
+    return {"terminal": "zellij", "session": sess, "pane_id": pane, "window": sess, "label": label}
+
+
+def _wezterm_location(env) -> dict | None:
+    pane = env.get("WEZTERM_PANE", "")
+    if not pane:
+        return None
+    loc = {"terminal": "wezterm", "pane_id": pane, "window": "", "label": f"wezterm pane {pane}"}
+    out = _run_quiet(["wezterm", "cli", "list", "--format", "json"])
+    try:
+        for p in json.loads(out or "[]"):
+            if str(p.get("pane_id")) == pane:
+                tab = p.get("tab_title") or p.get("title") or ""
+                loc.update({"window": tab, "window_id": p.get("window_id"), "tab_id": p.get("tab_id"),
+                            "label": f"wezterm win {p.get('window_id')} · tab {p.get('tab_id')} · {tab}".rstrip(" ·")})
+                break
+    except Exception:
+        pass
+    return loc
+
+
+def _kitty_location(env) -> dict | None:
+    wid = env.get("KITTY_WINDOW_ID", "")
+    if not wid:
+        return None
+    listen = env.get("KITTY_LISTEN_ON", "")
+    loc = {"terminal": "kitty", "window_id": wid, "listen_on": listen, "window": "", "label": f"kitty window {wid}"}
+    # Titles need remote control enabled; without it we still know the window id
+    cmd = ["kitty", "@"] + (["--to", listen] if listen else []) + ["ls"]
+    out = _run_quiet(cmd) if listen else None
+    try:
+        for os_win in json.loads(out or "[]"):
+            for tab in os_win.get("tabs", []):
+                for win in tab.get("windows", []):
+                    if str(win.get("id")) == wid:
+                        loc.update({"window": tab.get("title", ""),
+                                    "label": f"kitty {tab.get('title', '')} · {win.get('title', '')}".strip(" ·")})
+    except Exception:
+        pass
+    return loc
+
+
+def _iterm_location(env) -> dict | None:
+    if not env.get("ITERM_SESSION_ID"):
+        return None
+    iterm = iterm_info(env)
+    return {"terminal": "iterm2", "session_id": env["ITERM_SESSION_ID"].split(":")[-1],
+            "window": iterm["window"], "label": _location_label(iterm)}
+
+
+# Multiplexers first — a tmux pane inside kitty is addressed through tmux.
+LOCATION_PROVIDERS = (_tmux_location, _zellij_location, _wezterm_location, _kitty_location, _iterm_location)
+# TERM_PROGRAM names the emulator actually running the shell. Pane ids can be
+# inherited from another one (WEZTERM_PANE in an iTerm2 window opened from
+# WezTerm), so when it names a known emulator only that one is asked.
+TERM_PROGRAM_PROVIDERS = {"WezTerm": _wezterm_location, "kitty": _kitty_location, "iTerm.app": _iterm_location}
+
+
+def detect_location(env=None) -> dict:
+    """Work out which terminal pane fired the hook. Empty dict when unknown."""
+    env = os.environ if env is None else env
+    providers = LOCATION_PROVIDERS
+    emulator = TERM_PROGRAM_PROVIDERS.get(env.get("TERM_PROGRAM", ""))
+    if emulator:
+        providers = [p for p in providers if p is emulator or p not in TERM_PROGRAM_PROVIDERS.values()]
+    for provider in providers:
+        try:
+            loc = provider(env)
+        except Exception as e:
+            logging.error(f"Location provider {provider.__name__} failed: {e}")
+            loc = None
+        if loc:
+            return loc
+    return {}
+
+
+# Events whose handler stores the pane or notifies by default. A hook resolves
+# the pane for these before handing them to the collector; for anything else
+# the collector uses the pane recorded for the session.
+LOCATION_EVENTS = ("SessionStart", "UserPromptSubmit", "Stop", "SubagentStop", "Notification",
+                   "TaskCompleted", "TeammateIdle")
+
+
+def wants_location(event: str, data: dict) -> bool:
+    """Whether the hook should resolve the pane before forwarding this event."""
+    if event == "UserPromptSubmit":
+        return not str(data.get("prompt") or "").strip().startswith("<")
+    return event in LOCATION_EVENTS
+
+
+# ── GIT CONTEXT ──────────────────────────────────────────────
+# Repo root, branch, HEAD and dirty state of a session's cwd, looked up at
+# SessionStart and on every prompt. GIT_OPTIONAL_LOCKS=0 keeps `git status`
+# from refreshing the index under a running session.
+
+def git_context(cwd: str) -> dict:
+    """{'root', 'branch', 'commit', 'dirty'} for cwd, or {} outside a repo or without git."""
+    if not cwd or not os.path.isdir(cwd) or not shutil.which("git"):
+        return {}
+    env = {**os.environ, "GIT_OPTIONAL_LOCKS": "0"}
+
+    def git(*args) -> str | None:
+        try:
+            r = subprocess.run(["git", "-C", cwd, *args], capture_output=True, text=True, timeout=2, env=env)
+        except Exception:
+            return None
+        return r.stdout.strip() if r.returncode == 0 else None
+
+    root = git("rev-parse", "--show-toplevel")
+    if not root:
+        return {}
+    status = git("status", "--porcelain", "--untracked-files=no")
+    return {
+        "root": root,
+        "branch": git("symbolic-ref", "--short", "-q", "HEAD"),  # None when detached
+        "commit": git("rev-parse", "-q", "--verify", "HEAD"),  # None before the first commit
+        "dirty": None if status is None else bool(status),
+    }
+
+
+def wants_git(event: str, data: dict) -> bool:
+    """Events whose handler stores the repo state: session starts and real (non-system) prompts."""
+    if event == "SessionStart":
+        return True
+    return event == "UserPromptSubmit" and not str(data.get("prompt") or "").strip().startswith("<")
+
+
+def _sound_command(path: str) -> list[str] | None:
+    """Pick a player for this file: afplay on macOS, paplay/aplay on Linux."""
+    if shutil.which("afplay"):
+        return ["afplay", "-v", "0.7", path]
+    if shutil.which("paplay"):
+        return ["paplay", path]
+    # aplay only understands raw PCM containers
+    if shutil.which("aplay") and path.endswith(".wav"):
+        return ["aplay", "-q", path]
+    return None
+
+
+def play_sound(sound_key: str) -> None:
+    """Play the sound for this event type without blocking the hook."""
+    if load_config().get("sound", True) is False:
+        return
+    path = _pick_sound(sound_key)
+    cmd = _sound_command(path) if path else None
+    if cmd:
+        try:
+            subprocess.Popen(cmd, stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL)
+            logging.info(f"Playing sound: {os.path.basename(path)} for {sound_key}")
+        except Exception:
+            pass
+
+
+# ── NOTIFIER BACKENDS ────────────────────────────────────────
+
+class Notifier:
+    """A way of putting a notification in front of the user."""
+    name = ""
+
+    @classmethod
+    def available(cls) -> bool:
+        return True
+
+    def notify(self, title: str, subtitle: str, message: str = "", cwd: str | None = None) -> None:
+        raise NotImplementedError
+
+
+class MacNotifier(Notifier):
+    """terminal-notifier with click-to-focus iTerm, osascript as fallback."""
+    name = "macos"
+
+    @classmethod
+    def available(cls) -> bool:
+        return sys.platform == "darwin"
+
+    def notify(self, title, subtitle, message="", cwd=None):
+        try:
+            cmd = [
+                "terminal-notifier",
+                "-title", title,
+                "-subtitle", subtitle,
+            ]
+            if message:
+                cmd.extend(["-message", message])
+            # Use Finder as sender so macOS never suppresses the banner
+            # (banners are hidden when the sender app is focused — Finder is never focused)
+            cmd.extend(["-sender", "com.apple.Finder"])
+            # Click notification -> activate iTerm
+            cmd.extend(["-activate", "com.googlecode.iterm2"])
+            cmd.extend(["-ignoreDnD"])
+            if cwd:
+                cmd.extend(["-group", f"claude-{os.path.basename(cwd)}"])
+            subprocess.run(cmd, check=False, capture_output=True, timeout=5)
+        except FileNotFoundError:
+            # Fallback to osascript if terminal-notifier missing
+            safe_title = title.replace('"', '\\"')
+            safe_sub = subtitle.replace('"', '\\"')
+            script = f'display notification "{safe_sub}" with title "{safe_title}"'
+            subprocess.run(["osascript", "-e", script], check=False,
+                           capture_output=True, timeout=5)
+
+
+class FreedesktopNotifier(Notifier):
+    """org.freedesktop.Notifications via notify-send, or gdbus when libnotify is missing."""
+    name = "freedesktop"
+
+    @classmethod
+    def available(cls) -> bool:
+        return bool(os.environ.get("DBUS_SESSION_BUS_ADDRESS")) and bool(
+            shutil.which("notify-send") or shutil.which("gdbus"))
+
+    def notify(self, title, subtitle, message="", cwd=None):
+        body = "\n".join(p for p in (subtitle, message) if p)
+        if shutil.which("notify-send"):
+            cmd = ["notify-send", "--app-name=Claude Code", title, body]
+            if cwd:
+                # Same-project notifications replace each other where the server supports it
+                cmd.insert(1, f"--hint=string:x-canonical-private-synchronous:claude-{os.path.basename(cwd)}")
+        else:
+            cmd = ["gdbus", "call", "--session",
+                   "--dest", "org.freedesktop.Notifications",
+                   "--object-path", "/org/freedesktop/Notifications",
+                   "--method", "org.freedesktop.Notifications.Notify",
+                   "Claude Code", "0", "", title, body, "[]", "{}", "-1"]
+        subprocess.run(cmd, check=False, capture_output=True, timeout=5)
+
+
+class BellNotifier(Notifier):
+    """Ring the terminal bell on the controlling tty (tmux/screen turn this into an alert)."""
+    name = "bell"
+
+    def notify(self, title, subtitle, message="", cwd=None):
+        with open("/dev/tty", "w") as tty:
+            tty.write("\a")
+
+
+class LogNotifier(Notifier):
+    """Only write the notification to ccnotify.log."""
+    name = "log"
+
+    def notify(self, title, subtitle, message="", cwd=None):
+        pass
+
+
+NOTIFIERS: dict[str, type[Notifier]] = {
+    cls.name: cls for cls in (MacNotifier, FreedesktopNotifier, BellNotifier, LogNotifier)
+}
+
+
+def get_notifier() -> Notifier:
+    """Backend from $CCNOTIFY_NOTIFIER or config "notifier", else the first one available here."""
+    name = os.environ.get("CCNOTIFY_NOTIFIER") or load_config().get("notifier", "auto")
+    if name in NOTIFIERS:
+        return NOTIFIERS[name]()
+    if name != "auto":
+        logging.error(f"Unknown notifier {name!r} — auto-detecting")
+    for cls in (MacNotifier, FreedesktopNotifier):
+        if cls.available():
+            return cls()
+    return LogNotifier()
+
+
+def send_notification(title: str, subtitle: str, message: str = "",
+                      sound_key: str | None = "task_complete", cwd: str | None = None) -> None:
+    """Play the event sound and deliver the notification through the configured backend."""
+    if sound_key:
+        play_sound(sound_key)
+    notifier = get_notifier()
+    try:
+        notifier.notify(title, subtitle, message, cwd)
+        logging.info(f"Notified ({notifier.name}): {title} | {subtitle} | {message}")
+    except Exception as e:
+        logging.error(f"Notification error ({notifier.name}): {e}")
+
+
+# ── NOTIFICATION RULES ───────────────────────────────────────
+# rules.json holds an ordered list of {"match": {...}, "action": ...}. Every
+# event that could notify is described by a context dict (event, cwd, agent
+# type, duration, tool, message, ...) and the first rule whose match keys all
+# hold decides what happens. No match means the built-in behaviour: notify for
+# Stop, SubagentStop, Notification, TeammateIdle and TaskCompleted, stay quiet
+# for tool events.
+
+_rules: list[dict] | None = None
+
+
+def load_rules() -> list[dict]:
+    global _rules
+    if _rules is None:
+        _rules = []
+        try:
+            with open(RULES_PATH, "r", encoding="utf-8") as f:
+                obj = json.load(f)
+            rules = obj.get("rules", []) if isinstance(obj, dict) else obj
+            _rules = [r for r in rules if isinstance(r, dict)]
+        except FileNotFoundError:
+            pass
+        except Exception as e:
+            logging.error(f"Rules error in {RULES_PATH}: {e}")
+    return _rules
+
+
+def fmt_duration(secs: float | None) -> str:
+    """12s / 3m5s / 1h2m."""
+    if secs is None:
+        return ""
+    secs = int(secs)
+    if secs < 60:
+        return f"{secs}s"
+    if secs < 3600:
+        m, s = divmod(secs, 60)
+        return f"{m}m{s}s" if s else f"{m}m"
+    h, remainder = divmod(secs, 3600)
+    m = remainder // 60
+    return f"{h}h{m}m" if m else f"{h}h"
+
+
+def _as_list(value) -> list:
+    return value if isinstance(value, list) else [value]
+
+
+def rule_matches(match: dict, ctx: dict) -> bool:
+    """True when every key in `match` holds for the event context."""
+    for key, want in match.items():
+        if key in ("event", "agent_type", "tool"):
+            if ctx.get(key) not in _as_list(want):
+                return False
+        elif key == "cwd":
+            cwd = ctx.get("cwd") or ""
+            if not any(fnmatch.fnmatch(cwd, os.path.expanduser(g)) for g in _as_list(want)):
+                return False
+        elif key == "message":
+            if not any(re.search(p, ctx.get("message") or "", re.I) for p in _as_list(want)):
+                return False
+        elif key == "min_duration":
+            if ctx.get("duration") is None or ctx["duration"] < float(want):
+                return False
+        elif key == "max_duration":
+            if ctx.get("duration") is None or ctx["duration"] > float(want):
+                return False
+        else:
+            logging.warning(f"Rules: unknown match key {key!r}")
+            return False
+    return True
+
+
+def _normalize_actions(action) -> list[dict]:
+    """"notify" / ["sound", {"run": "..."}] / {"notify": true, "sound": "permission"} -> list of dicts."""
+    out = []
+    for a in _as_list(action):
+        if isinstance(a, str):
+            out.append({a: True})
+        elif isinstance(a, dict):
+            out.append(a)
+    return out
+
+
+def evaluate_rules(ctx: dict) -> list[dict] | None:
+    """Actions from the first matching rule, or None to fall back to the default."""
+    for i, rule in enumerate(load_rules()):
+        try:
+            if rule_matches(rule.get("match", {}), ctx):
+                ctx["rule"] = rule.get("name") or f"#{i + 1}"
+                logging.info(f"Rule {ctx['rule']} matched {ctx.get('event')}")
+                return _normalize_actions(rule.get("action", "notify"))
+        except (re.error, TypeError, ValueError) as e:
+            logging.error(f"Rules: rule {rule.get('name') or i + 1} skipped: {e}")
+    return None
+
+
+def _fill(template: str, ctx: dict) -> str:
+    """str.format with the event context; unknown fields are left as-is."""
+    class Ctx(dict):
+        def __missing__(self, key):
+            return "{" + key + "}"
+    try:
+        return str(template).format_map(Ctx({k: ("" if v is None else v) for k, v in ctx.items()}))
+    except (ValueError, IndexError):
+        return str(template)
+
+
+def run_rule_command(command: str, ctx: dict) -> None:
+    """Run a rule's shell command in the background with the event in CCNOTIFY_* variables."""
+    env = dict(os.environ)
+    for key in ("event", "session_id", "cwd", "title", "subtitle", "message", "agent_type", "tool", "duration", "path"):
+        if ctx.get(key) is not None:
+            env[f"CCNOTIFY_{key.upper()}"] = str(ctx[key])
+    try:
+        subprocess.Popen(command, shell=True, env=env,
+                         cwd=ctx["cwd"] if os.path.isdir(ctx.get("cwd") or "") else None,
+                         stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL, start_new_session=True)
+        logging.info(f"Rule command: {command}")
+    except Exception as e:
+        logging.error(f"Rule command failed: {command}: {e}")
+
+
+def perform_actions(actions: list[dict], ctx: dict, deliver=None, effect=None) -> bool:
+    """notify / sound / run in order; suppress stops the rest and returns True.
+
+    `deliver(ctx, title, subtitle, sound_key, urgent)` replaces the direct
+    send_notification call — the tracker passes its coalescing policy.
+    `effect(fn, *args)` runs sounds and commands; the collector passes one
+    that holds them until its batch has committed.
+    """
+    effect = effect or (lambda fn, *args: fn(*args))
+    for action in actions:
+        if action.get("suppress"):
+            logging.info(f"Suppressed {ctx.get('event')}: {ctx.get('subtitle')}")
+            return True
+        subtitle = _fill(action["subtitle"], ctx) if action.get("subtitle") else ctx.get("subtitle", "")
+        title = _fill(action["title"], ctx) if action.get("title") else ctx.get("title", "")
+        # "sound": "permission" picks the sound, "sound": false notifies silently
+        sound = action.get("sound")
+        sound_key = sound if isinstance(sound, str) else None if sound is False else ctx.get("sound")
+        if action.get("notify"):
+            if deliver:
+                deliver(ctx, title, subtitle, sound_key, bool(action.get("urgent")))
+            else:
+                effect(send_notification, title, subtitle, ctx.get("loc", ""), sound_key, ctx.get("cwd"))
+        elif sound and (action.get("urgent") or quiet_until() is None):
+            effect(play_sound, sound_key)
+        if action.get("run"):
+            effect(run_rule_command, _fill(action["run"], ctx), ctx)
+    return False
+
+
+# ── NOTIFICATION POLICY ──────────────────────────────────────
+# What happens to a notification once the rules said "notify". Completions
+# (agents, tasks, idle teammates) are held for a short window and sent as one
+# summary per session; each session gets at most `per_session` banners per
+# `window` seconds, with the overflow folded into the next summary; and during
+# quiet hours nothing sounds — held notifications go out as one digest when
+# they end. Pending and recently sent notifications live in notify_queue so
+# that separate hook processes can see each other.
+
+NOTIFY_DEFAULTS = {
+    "coalesce_window": 3,   # seconds to gather completions into one notification; 0 disables
+    "rate_limit": {"per_session": 6, "window": 60},
+    "quiet_hours": None,    # {"start": "22:00", "end": "08:00", "digest": true}
+}
+# Events whose notifications are gathered into a summary instead of sent one by one
+COALESCE_EVENTS = {"SubagentStop", "TaskCompleted", "TeammateIdle", "PreToolUse", "PostToolUse", "PostToolUseFailure"}
+HELD_REASONS = {"batch": "coalescing", "rate": "rate limit", "quiet": "quiet hours"}
+SUMMARY_NOUNS = {
+    "SubagentStop": ("agent done", "agents done"),
+    "TaskCompleted": ("task completed", "tasks completed"),
+    "TeammateIdle": ("teammate idle", "teammates idle"),
+    "Stop": ("turn done", "turns done"),
+}
+
+
+def notify_config() -> dict:
+    cfg = {k: (dict(v) if isinstance(v, dict) else v) for k, v in NOTIFY_DEFAULTS.items()}
+    user = load_config().get("notify", {})
+    if isinstance(user, dict):
+        for key, value in user.items():
+            if isinstance(cfg.get(key), dict) and isinstance(value, dict):
+                cfg[key].update(value)
+            else:
+                cfg[key] = value
+    return cfg
+
+
+def _clock(value: str) -> tuple[int, int]:
+    h, _, m = str(value).partition(":")
+    return int(h), int(m or 0)
+
+
+def quiet_until(now: float | None = None) -> float | None:
+    """End of the current quiet period as an epoch, or None outside quiet hours."""
+    quiet = notify_config().get("quiet_hours")
+    if not isinstance(quiet, dict) or not quiet.get("start") or not quiet.get("end"):
+        return None
+    try:
+        start, end = _clock(quiet["start"]), _clock(quiet["end"])
+    except ValueError:
+        logging.error(f"Bad quiet_hours: {quiet}")
+        return None
+    local = datetime.fromtimestamp(now if now is not None else time.time())
+    t = (local.hour, local.minute)
+    if start <= end:
+        inside = start <= t < end
+    else:  # wraps midnight
+        inside = t >= start or t < end
+    if not inside:
+        return None
+    end_at = local.replace(hour=end[0], minute=end[1], second=0, microsecond=0)
+    if end_at <= local:
+        end_at += timedelta(days=1)
+    return end_at.timestamp()
+
+
+def _plural(n: int, nouns: tuple[str, str]) -> str:
+    return f"{n} {nouns[0] if n == 1 else nouns[1]}"
+
+
+def summarize(rows: list[tuple]) -> str:
+    """'6 agents done, 2 tasks completed' from (event, subtitle) rows."""
+    counts: dict[str, int] = {}
+    for event, _ in rows:
+        key = event if event in SUMMARY_NOUNS else "other"
+        counts[key] = counts.get(key, 0) + 1
+    parts = [_plural(n, SUMMARY_NOUNS[e]) for e, n in counts.items() if e != "other"]
+    if counts.get("other"):
+        others = {st for e, st in rows if e not in SUMMARY_NOUNS}
+        n = counts["other"]
+        parts.append(f"{others.pop()} ×{n}" if len(others) == 1 and n > 1 else
+                     _plural(n, ("notification", "notifications")))
+    return ", ".join(parts)
+
+
+# ── EDIT CONFLICTS ───────────────────────────────────────────
+# Two live writers — different sessions, or parallel subagents of one session —
+# editing the same absolute path within `window` seconds of each other. Each
+# pair and path is one row in the conflict table; the first edit that finds it
+# raises a "Conflict" notification, and agent-top badges both sessions while
+# they stay live and the edits keep within the window.
+
+CONFLICT_DEFAULTS = {
+    "window": 600,  # seconds between the two edits; 0 disables detection
+}
+# file_touch ops that count as modifying a file
+WRITE_OPS = ("write", "edit")
+
+
+def conflict_config() -> dict:
+    cfg = dict(CONFLICT_DEFAULTS)
+    user = load_config().get("conflicts", {})
+    if isinstance(user, dict):
+        cfg.update(user)
+    return cfg
+
+
+# ── REDACTION ────────────────────────────────────────────────
+# Applied to tool inputs, responses and error text before they are stored, and
+# again by agent-top before display (for rows written by older versions). Each
+# detector replaces the `secret` group (or the whole match) with a marker.
+# Secret groups never span quotes or backslashes, so redacting a JSON string
+# leaves it valid JSON.
+
+_SECRET_KEYS = r"(?:api[_-]?key|apikey|secret|token|passw(?:or)?d|pwd|credentials?|private[_-]?key|access[_-]?key|client[_-]?secret)"
+_NOT_MASKED = r"(?!\[REDACTED)"  # keeps redaction idempotent when agent-top re-applies it
+_VALUE = r"(?:\\?[\"'])?(?P<secret>" + _NOT_MASKED + r"[^\s\"'\\]+)"
+
+REDACTION_DETECTORS = [
+    ("private_key", r"-----BEGIN [A-Z0-9 ]*PRIVATE KEY-----.*?-----END [A-Z0-9 ]*PRIVATE KEY-----"),
+    ("auth_header", r"(?i)\b(?:proxy-)?authorization\s*[:=]\s*(?:(?:bearer|basic|token|digest)\s+)?(?!(?:bearer|basic|token|digest)\b)" + _VALUE),
+    ("bearer", r"(?i)\bbearer\s+(?P<secret>[A-Za-z0-9._~+/=-]{12,})"),
+    ("aws_key", r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b"),
+    ("github_token", r"\b(?:gh[pousr]_[A-Za-z0-9]{36,}|github_pat_[A-Za-z0-9_]{22,})"),
+    ("api_key", r"\bsk-(?:ant-|proj-)?[A-Za-z0-9_-]{20,}"),
+    ("slack_token", r"\bxox[abposr]-[A-Za-z0-9-]{10,}"),
+    ("google_key", r"\bAIza[0-9A-Za-z_-]{35}"),
+    ("stripe_key", r"\b[rsp]k_(?:live|test)_[0-9A-Za-z]{16,}"),
+    ("jwt", r"\beyJ[A-Za-z0-9_-]{8,}\.eyJ[A-Za-z0-9_-]{8,}\.[A-Za-z0-9_-]{8,}"),
+    ("url_password", r"(?i)\b[a-z][a-z0-9+.-]*://[^\s:/@\"'\\]+:(?P<secret>" + _NOT_MASKED + r"[^\s@/\"'\\]+)@"),
+    # FOO_API_KEY=..., export DB_PASSWORD="..." — upper-case names only, so `tokens=5` survives
+    ("env_assignment", r"\b[A-Z0-9_]*(?:KEY|SECRET|TOKEN|PASSWORD|PASSWD|PWD|CREDENTIALS?)[A-Z0-9_]*\s*=\s*" + _VALUE),
+    ("cli_flag", r"(?i)(?<![\w-])--?" + _SECRET_KEYS + r"(?:=|\s+)" + _VALUE),
+    ("json_field", r"(?i)\\?\"[\w-]*" + _SECRET_KEYS + r"\\?\"\s*:\s*\\?\"(?P<secret>" + _NOT_MASKED + r"[^\"\\]+)\\?\""),
+]
+
+_redactors: list[tuple[str, re.Pattern]] | None = None
+
+
+def redactors() -> list[tuple[str, re.Pattern]]:
+    """Built-in detectors plus `redaction.patterns` from config.json, compiled once."""
+    global _redactors
+    if _redactors is None:
+        cfg = load_config().get("redaction", {})
+        cfg = cfg if isinstance(cfg, dict) else {}
+        _redactors = []
+        if cfg.get("enabled", True) is False:
+            return _redactors
+        specs = [] if cfg.get("builtin", True) is False else list(REDACTION_DETECTORS)
+        for i, pat in enumerate(cfg.get("patterns", [])):
+            if isinstance(pat, dict):
+                specs.append((pat.get("name") or f"custom{i + 1}", pat.get("regex", "")))
+            else:
+                specs.append((f"custom{i + 1}", str(pat)))
+        for name, regex in specs:
+            try:
+                _redactors.append((name, re.compile(regex, re.S)))
+            except re.error as e:
+                logging.error(f"Redaction pattern {name!r} ignored: {e}")
+    return _redactors
+
+
+def redact_text(text: str) -> tuple[str, int]:
+    """Return (text with secrets masked, number of secrets masked)."""
+    if not text:
+        return text, 0
+    total = 0
+    for name, rx in redactors():
+        marker = f"[REDACTED:{name}]"
+
+        def mask(m, marker=marker):
+            if "secret" in rx.groupindex and m.group("secret") is not None:
+                a, b = m.span("secret")
+                return m.group(0)[:a - m.start()] + marker + m.group(0)[b - m.start():]
+            return marker
+
+        text, n = rx.subn(mask, text)
+        total += n
+    return text, total
+
+
+def redact_obj(obj) -> tuple[object, int]:
+    """Copy of a decoded JSON value with every string redacted in place.
+
+    Strings are redacted before serialising because json.dumps turns newlines
+    into a literal \\n, which hides line starts (a .env file's `FOO_SECRET=`) from
+    the detectors.
+    """
+    if isinstance(obj, str):
+        return redact_text(obj)
+    if isinstance(obj, dict):
+        out, total = {}, 0
+        for key, val in obj.items():
+            out[key], n = redact_obj(val)
+            total += n
+        return out, total
+    if isinstance(obj, (list, tuple)):
+        out, total = [], 0
+        for val in obj:
+            val, n = redact_obj(val)
+            out.append(val)
+            total += n
+        return out, total
+    return obj, 0
+
+
+def redact_json(obj, limit: int | None = 4000) -> tuple[str, int]:
+    """Serialise a hook payload field for storage: redact each string, then the JSON, then truncate.
+
+    The second pass catches secrets only visible with their key, like {"api_key": "..."}.
+    """
+    obj, n = redact_obj(obj)
+    text, m = redact_text(json.dumps(obj, default=str))
+    return text[:limit], n + m
+
+
+# ── DIFFS ──────────────────────────────────────────────────
+# Edit, MultiEdit and Write calls are stored with a unified diff, so agent-top
+# can show what changed without the 4000-character tool_input. The hunks come
+# from the response's structuredPatch (real line numbers) when Claude Code sends
+# one, else from the call's own old/new strings. Diffs are redacted and capped
+# at DIFF_LIMIT characters on a line boundary, or inside a line that would
+# otherwise take most of the budget with it (marked with "…").
+
+DIFF_TOOLS = ("Edit", "MultiEdit", "Write")
+DIFF_LIMIT = 20000
+
+
+def _hunks_from_patch(patch) -> list[str]:
+    lines = []
+    for hunk in patch if isinstance(patch, list) else []:
+        if not isinstance(hunk, dict):
+            continue
+        lines.append(f"@@ -{hunk.get('oldStart', 0)},{hunk.get('oldLines', 0)} "
+                     f"+{hunk.get('newStart', 0)},{hunk.get('newLines', 0)} @@")
+        lines.extend(str(line) for line in hunk.get("lines") or [])
+    return lines
+
+
+def _hunks_from_strings(old: str, new: str) -> list[str]:
+    diff = difflib.unified_diff(str(old or "").splitlines(), str(new or "").splitlines(), lineterm="")
+    return [line for line in diff if not line.startswith(("---", "+++"))]
+
+
+def build_diff(tool_name: str, tool_input: dict, tool_response, path: str) -> tuple[str, int, int, bool] | None:
+    """(unified diff, lines added, lines removed, truncated) for a file-changing call, or None.
+
+    `path` is the file's absolute path, as stored in file_diff; the headers use it as-is.
+    """
+    if tool_name not in DIFF_TOOLS or not isinstance(tool_input, dict) or not path:
+        return None
+    response = tool_response if isinstance(tool_response, dict) else {}
+    hunks = _hunks_from_patch(response.get("structuredPatch"))
+    if not hunks:
+        if tool_name == "Edit":
+            hunks = _hunks_from_strings(tool_input.get("old_string"), tool_input.get("new_string"))
+        elif tool_name == "MultiEdit":
+            for edit in tool_input.get("edits") or []:
+                if isinstance(edit, dict):
+                    hunks += _hunks_from_strings(edit.get("old_string"), edit.get("new_string"))
+        else:
+            hunks = _hunks_from_strings(response.get("originalFile") or "", tool_input.get("content"))
+    if not hunks:
+        return None
+    text, _ = redact_text("\n".join([f"--- {path}", f"+++ {path}", *hunks]))
+    body = text.split("\n")[2:]
+    added = sum(1 for line in body if line.startswith("+"))
+    removed = sum(1 for line in body if line.startswith("-"))
+    truncated = len(text) > DIFF_LIMIT
+    if truncated:
+        cut = text.rfind("\n", 0, DIFF_LIMIT)
+        if cut < DIFF_LIMIT // 2:
+            # One huge line (minified file, lockfile): keep its start rather than only the headers
+            text = text[:DIFF_LIMIT - 1] + "…"
+        else:
+            text = text[:cut]
+    return text, added, removed, truncated
+
+
+# ── SESSION STATE ────────────────────────────────────────────
+# Every session is in exactly one state, moved by its own hook events (never a
+# subagent's). Each stretch is a session_state row; the open one (ended_at NULL)
+# is the current state, and closed rows carry how long it lasted, so agent-top
+# can show time working vs time blocked on you. ended and crashed are terminal
+# and stored already closed; crashed is recorded by reap when the process dies
+# without a SessionEnd.
+
+SESSION_STATES = ("starting", "working", "waiting_input", "waiting_permission", "idle", "ended", "crashed")
+
+# Hook event -> state it puts the session in. Notifications are mapped by wait kind instead.
+EVENT_STATES = {
+    "SessionStart": "starting",
+    "UserPromptSubmit": "working",
+    "PreToolUse": "working",
+    "PostToolUse": "working",
+    "PostToolUseFailure": "working",
+    "PreCompact": "working",
+    "Stop": "idle",
+    "TeammateIdle": "idle",
+    "SessionEnd": "ended",
+}
+WAIT_STATES = {"input": "waiting_input", "permission": "waiting_permission", "action": "waiting_input"}
+BLOCKED_STATES = ("waiting_input", "waiting_permission")
+TERMINAL_STATES = ("ended", "crashed")
+
+
+def set_session_state(conn, session_id: str, state: str, event: str, at: str | None = None) -> bool:
+    """Move a session to `state` at `at` (default now), closing the stretch it was in.
+
+    A no-op when the session is already in that state. Returns whether a
+    transition was recorded.
+    """
+    row = conn.execute(
+        "SELECT state FROM session_state WHERE session_id = ? ORDER BY id DESC LIMIT 1", (session_id,)
+    ).fetchone()
+    if row and row[0] == state:
+        return False
+    at = at or datetime.now(timezone.utc).strftime("%Y-%m-%d %H:%M:%S")
+    conn.execute(
+        """UPDATE session_state SET ended_at = ?,
+                                    duration_ms = MAX(0, CAST(ROUND((julianday(?) - julianday(started_at)) * 86400000) AS INTEGER))
+           WHERE session_id = ? AND ended_at IS NULL""",
+        (at, at, session_id),
+    )
+    terminal = state in TERMINAL_STATES
+    conn.execute(
+        "INSERT INTO session_state (session_id, state, event, started_at, ended_at, duration_ms) VALUES (?, ?, ?, ?, ?, ?)",
+        (session_id, state, event, at, at if terminal else None, 0 if terminal else None),
+    )
+    return True
+
+
+# ── SCHEMA MIGRATIONS ────────────────────────────────────────
+# The one place the database layout is defined. Hooks, the collector and
+# agent-top all run these. Each migration returns the SQL it still needs for
+# the database in front of it (so re-running one is harmless and --dry-run can
+# show exactly what would change); PRAGMA user_version records the last one
+# applied. Append new migrations — never edit or reorder shipped ones.
+
+
+def _table_columns(conn, table: str) -> set[str]:
+    return {r[1] for r in conn.execute(f"PRAGMA table_info({table})")}
+
+
+def _add_columns(conn, table: str, columns: list[tuple[str, str]]) -> list[str]:
+    have = _table_columns(conn, table)
+    return [f"ALTER TABLE {table} ADD COLUMN {col} {ctype}" for col, ctype in columns if col not in have]
+
+
+def _m1_base(conn) -> list[str]:
+    """Tables as they existed before versioning."""
+    return [
+        """CREATE TABLE IF NOT EXISTS prompt (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               session_id TEXT NOT NULL,
+               created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+               prompt TEXT,
+               cwd TEXT,
+               seq INTEGER,
+               stoped_at DATETIME,
+               lastWaitUserAt DATETIME
+           )""",
+        """CREATE TRIGGER IF NOT EXISTS auto_increment_seq
+           AFTER INSERT ON prompt
+           FOR EACH ROW
+           BEGIN
+               UPDATE prompt SET seq = (
+                   SELECT COALESCE(MAX(seq), 0) + 1
+                   FROM prompt WHERE session_id = NEW.session_id
+               ) WHERE id = NEW.id;
+           END""",
+        """CREATE TABLE IF NOT EXISTS agent (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               agent_id TEXT NOT NULL UNIQUE,
+               agent_type TEXT NOT NULL,
+               session_id TEXT NOT NULL,
+               cwd TEXT,
+               started_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+               stopped_at DATETIME,
+               transcript_path TEXT
+           )""",
+        """CREATE TABLE IF NOT EXISTS tool_event (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               session_id TEXT NOT NULL,
+               tool_name TEXT NOT NULL,
+               tool_label TEXT NOT NULL,
+               created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+           )""",
+        """CREATE INDEX IF NOT EXISTS idx_tool_event_session
+               ON tool_event (session_id, created_at DESC)""",
+        """CREATE TABLE IF NOT EXISTS team_session (
+               session_id TEXT PRIMARY KEY,
+               team_name TEXT NOT NULL,
+               teammate_name TEXT NOT NULL,
+               last_seen_at DATETIME DEFAULT CURRENT_TIMESTAMP
+           )""",
+    ]
+
+
+def _m2_columns(conn) -> list[str]:
+    """Columns that used to be bolted on by whichever side noticed them missing."""
+    return _add_columns(conn, "prompt", [("pid", "INTEGER"), ("location", "TEXT")]) + _add_columns(
+        conn, "tool_event", [
+            ("tool_input", "TEXT"), ("tool_response", "TEXT"), ("tool_use_id", "TEXT"),
+            ("duration_ms", "INTEGER"), ("cwd", "TEXT"),
+            ("is_error", "INTEGER DEFAULT 0"), ("error_message", "TEXT"),
+        ])
+
+
+def _m3_rename_stopped_at(conn) -> list[str]:
+    if "stoped_at" not in _table_columns(conn, "prompt"):
+        return []
+    return ["ALTER TABLE prompt RENAME COLUMN stoped_at TO stopped_at"]
+
+
+def _m4_dashboard_indexes(conn) -> list[str]:
+    """Indexes for the queries agent-top runs on every refresh."""
+    return [
+        "CREATE INDEX IF NOT EXISTS idx_prompt_session ON prompt (session_id, stopped_at)",
+        "CREATE INDEX IF NOT EXISTS idx_prompt_stopped ON prompt (stopped_at)",
+        "CREATE INDEX IF NOT EXISTS idx_agent_session ON agent (session_id, stopped_at)",
+        "CREATE INDEX IF NOT EXISTS idx_agent_stopped ON agent (stopped_at)",
+        "CREATE INDEX IF NOT EXISTS idx_agent_started ON agent (started_at)",
+        "CREATE INDEX IF NOT EXISTS idx_tool_event_use_id ON tool_event (tool_use_id)",
+        "CREATE INDEX IF NOT EXISTS idx_tool_event_created ON tool_event (created_at)",
+    ]
+
+
+def _m5_daily_aggregates(conn) -> list[str]:
+    """Daily rollups of pruned rows, so STATS ranges outlive retention."""
+    return [
+        """CREATE TABLE IF NOT EXISTS tool_daily (
+               day TEXT NOT NULL,
+               tool_name TEXT NOT NULL,
+               cwd TEXT NOT NULL DEFAULT '',
+               calls INTEGER NOT NULL DEFAULT 0,
+               errors INTEGER NOT NULL DEFAULT 0,
+               duration_ms INTEGER NOT NULL DEFAULT 0,
+               PRIMARY KEY (day, tool_name, cwd)
+           )""",
+        """CREATE TABLE IF NOT EXISTS agent_daily (
+               day TEXT NOT NULL,
+               agent_type TEXT NOT NULL,
+               cwd TEXT NOT NULL DEFAULT '',
+               runs INTEGER NOT NULL DEFAULT 0,
+               PRIMARY KEY (day, agent_type, cwd)
+           )""",
+    ]
+
+
+def _m6_redactions(conn) -> list[str]:
+    return _add_columns(conn, "tool_event", [("redactions", "INTEGER DEFAULT 0")])
+
+
+def _m7_usage(conn) -> list[str]:
+    """Token usage per assistant message, and how far each transcript has been read."""
+    return [
+        """CREATE TABLE IF NOT EXISTS transcript (
+               path TEXT PRIMARY KEY,
+               session_id TEXT NOT NULL,
+               agent_id TEXT,
+               offset INTEGER NOT NULL DEFAULT 0,
+               updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+           )""",
+        """CREATE TABLE IF NOT EXISTS usage (
+               message_id TEXT PRIMARY KEY,
+               session_id TEXT NOT NULL,
+               agent_id TEXT,
+               prompt_id INTEGER,
+               model TEXT,
+               input_tokens INTEGER NOT NULL DEFAULT 0,
+               output_tokens INTEGER NOT NULL DEFAULT 0,
+               cache_write_tokens INTEGER NOT NULL DEFAULT 0,
+               cache_read_tokens INTEGER NOT NULL DEFAULT 0,
+               cost_usd REAL NOT NULL DEFAULT 0,
+               created_at DATETIME
+           )""",
+        "CREATE INDEX IF NOT EXISTS idx_usage_session ON usage (session_id, agent_id)",
+        "CREATE INDEX IF NOT EXISTS idx_usage_created ON usage (created_at)",
+    ]
+
+
+def _m8_notify_queue(conn) -> list[str]:
+    """Notifications held back or already sent; due_at/sent_at are epoch seconds."""
+    return [
+        """CREATE TABLE IF NOT EXISTS notify_queue (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               session_id TEXT,
+               cwd TEXT,
+               event TEXT,
+               title TEXT,
+               subtitle TEXT,
+               loc TEXT,
+               sound TEXT,
+               kind TEXT NOT NULL,
+               created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+               due_at REAL,
+               sent_at REAL
+           )""",
+        "CREATE INDEX IF NOT EXISTS idx_notify_queue_pending ON notify_queue (sent_at, due_at)",
+        "CREATE INDEX IF NOT EXISTS idx_notify_queue_session ON notify_queue (session_id, sent_at)",
+    ]
+
+
+def _m9_notification(conn) -> list[str]:
+    """Every notification ccnotify decided on, with what became of it."""
+    return [
+        """CREATE TABLE IF NOT EXISTS notification (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               session_id TEXT,
+               cwd TEXT,
+               event TEXT,
+               title TEXT,
+               subtitle TEXT,
+               kind TEXT,
+               status TEXT NOT NULL,
+               reason TEXT,
+               queue_id INTEGER,
+               created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+           )""",
+        "CREATE INDEX IF NOT EXISTS idx_notification_created ON notification (created_at)",
+        "CREATE INDEX IF NOT EXISTS idx_notification_queue ON notification (queue_id)",
+    ]
+
+
+def _m10_event_log(conn) -> list[str]:
+    """Every hook payload as received (after redaction) — the tables above are rebuilt from it."""
+    return [
+        """CREATE TABLE IF NOT EXISTS event (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               event TEXT NOT NULL,
+               session_id TEXT,
+               payload TEXT NOT NULL,
+               pid INTEGER,
+               env TEXT,
+               location TEXT,
+               created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+           )""",
+        "CREATE INDEX IF NOT EXISTS idx_event_session ON event (session_id)",
+        "CREATE INDEX IF NOT EXISTS idx_event_created ON event (created_at)",
+    ]
+
+
+def _m11_compaction(conn) -> list[str]:
+    """Context compaction markers from PreCompact."""
+    return [
+        """CREATE TABLE IF NOT EXISTS compaction (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               session_id TEXT,
+               trigger TEXT,
+               custom_instructions TEXT,
+               created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+           )""",
+        "CREATE INDEX IF NOT EXISTS idx_compaction_session ON compaction (session_id, created_at)",
+    ]
+
+
+def _m12_tool_agent(conn) -> list[str]:
+    """Which agent ran each tool call, and which Task call spawned each agent."""
+    return _add_columns(conn, "tool_event", [("agent_id", "TEXT")]) + _add_columns(
+        conn, "agent", [("tool_use_id", "TEXT")]) + [
+        "CREATE INDEX IF NOT EXISTS idx_tool_event_agent ON tool_event (agent_id)",
+    ]
+
+
+def _m13_parent_agent(conn) -> list[str]:
+    """Agent that spawned each agent, for nested subagents."""
+    return _add_columns(conn, "agent", [("parent_agent_id", "TEXT")]) + [
+        "CREATE INDEX IF NOT EXISTS idx_agent_parent ON agent (parent_agent_id)",
+    ]
+
+
+def _m14_session_context(conn) -> list[str]:
+    """Git, model, permission mode and start source on prompt rows; the git snapshot in the event log."""
+    return _add_columns(conn, "prompt", [
+        ("git_root", "TEXT"), ("git_branch", "TEXT"), ("git_commit", "TEXT"), ("git_dirty", "INTEGER"),
+        ("model", "TEXT"), ("permission_mode", "TEXT"), ("source", "TEXT"),
+    ]) + _add_columns(conn, "event", [("git", "TEXT")])
+
+
+def _m15_file_touch(conn) -> list[str]:
+    """Files each session and agent read, wrote, edited or searched."""
+    return [
+        """CREATE TABLE IF NOT EXISTS file_touch (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               session_id TEXT NOT NULL,
+               agent_id TEXT,
+               tool_use_id TEXT,
+               path TEXT NOT NULL,
+               op TEXT NOT NULL,
+               created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+           )""",
+        "CREATE INDEX IF NOT EXISTS idx_file_touch_session ON file_touch (session_id, path)",
+        "CREATE INDEX IF NOT EXISTS idx_file_touch_path ON file_touch (path, created_at)",
+        "CREATE INDEX IF NOT EXISTS idx_file_touch_created ON file_touch (created_at)",
+    ]
+
+
+def _m16_conflict(conn) -> list[str]:
+    """Paths two live sessions or agents edited within the conflict window."""
+    return [
+        """CREATE TABLE IF NOT EXISTS conflict (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               path TEXT NOT NULL,
+               session_a TEXT NOT NULL,
+               agent_a TEXT NOT NULL DEFAULT '',
+               session_b TEXT NOT NULL,
+               agent_b TEXT NOT NULL DEFAULT '',
+               created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+               last_seen_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+               UNIQUE (path, session_a, agent_a, session_b, agent_b)
+           )""",
+        "CREATE INDEX IF NOT EXISTS idx_conflict_seen ON conflict (last_seen_at)",
+    ]
+
+
+def _m17_file_diff(conn) -> list[str]:
+    """Unified diffs of Edit, MultiEdit and Write calls."""
+    return [
+        """CREATE TABLE IF NOT EXISTS file_diff (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               session_id TEXT NOT NULL,
+               agent_id TEXT,
+               tool_use_id TEXT,
+               tool_name TEXT,
+               path TEXT NOT NULL,
+               diff TEXT,
+               added INTEGER DEFAULT 0,
+               removed INTEGER DEFAULT 0,
+               truncated INTEGER DEFAULT 0,
+               created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+           )""",
+        "CREATE INDEX IF NOT EXISTS idx_file_diff_session ON file_diff (session_id, path)",
+        "CREATE INDEX IF NOT EXISTS idx_file_diff_tool_use ON file_diff (tool_use_id)",
+        "CREATE INDEX IF NOT EXISTS idx_file_diff_created ON file_diff (created_at)",
+    ]
+
+
+def _m18_stalled_at(conn) -> list[str]:
+    """When the watchdog last flagged a session (on its prompt row) or an agent as stalled."""
+    return _add_columns(conn, "prompt", [("stalled_at", "DATETIME")]) + _add_columns(
+        conn, "agent", [("stalled_at", "DATETIME")])
+
+
+def _m19_wait(conn) -> list[str]:
+    """Stretches a session spent blocked on the user: input, permission or another choice."""
+    return [
+        """CREATE TABLE IF NOT EXISTS wait (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               session_id TEXT NOT NULL,
+               kind TEXT,
+               message TEXT,
+               started_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+               ended_at DATETIME,
+               duration_ms INTEGER
+           )""",
+        "CREATE INDEX IF NOT EXISTS idx_wait_session ON wait (session_id, ended_at)",
+        "CREATE INDEX IF NOT EXISTS idx_wait_started ON wait (started_at)",
+    ]
+
+
+def _m21_event_redactions(conn) -> list[str]:
+    """Secrets the handlers masked per event, which a rebuild can't recount from the redacted payload."""
+    return _add_columns(conn, "event", [("redactions", "INTEGER")])
+
+
+def _m20_session_state(conn) -> list[str]:
+    """State transitions per session, each row one stretch spent in a state."""
+    return [
+        """CREATE TABLE IF NOT EXISTS session_state (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               session_id TEXT NOT NULL,
+               state TEXT NOT NULL,
+               event TEXT,
+               started_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+               ended_at DATETIME,
+               duration_ms INTEGER
+           )""",
+        "CREATE INDEX IF NOT EXISTS idx_session_state_session ON session_state (session_id, ended_at)",
+        "CREATE INDEX IF NOT EXISTS idx_session_state_started ON session_state (started_at)",
+    ]
+
+
+MIGRATIONS = [
+    (1, "base tables", _m1_base),
+    (2, "pid, location, tool response and error columns", _m2_columns),
+    (3, "rename prompt.stoped_at to stopped_at", _m3_rename_stopped_at),
+    (4, "indexes for dashboard queries", _m4_dashboard_indexes),
+    (5, "tool_daily and agent_daily aggregates", _m5_daily_aggregates),
+    (6, "tool_event.redactions", _m6_redactions),
+    (7, "usage and transcript tables", _m7_usage),
+    (8, "notify_queue for coalescing, rate limits and quiet hours", _m8_notify_queue),
+    (9, "notification history", _m9_notification),
+    (10, "raw hook event log", _m10_event_log),
+    (11, "compaction markers", _m11_compaction),
+    (12, "tool_event.agent_id and agent.tool_use_id", _m12_tool_agent),
+    (13, "agent.parent_agent_id", _m13_parent_agent),
+    (14, "session context: git, model, permission mode, source", _m14_session_context),
+    (15, "file_touch index", _m15_file_touch),
+    (16, "edit conflicts", _m16_conflict),
+    (17, "file_diff", _m17_file_diff),
+    (18, "watchdog stalled_at", _m18_stalled_at),
+    (19, "wait log", _m19_wait),
+    (20, "session state transitions", _m20_session_state),
+    (21, "event.redactions", _m21_event_redactions),
+]
+# Kept literal: agent-top greps it out of the installed hook to tell whether
+# the hook understands the schema it is about to migrate to.
+SCHEMA_VERSION = 21
+
+
+def schema_version(conn) -> int:
+    return conn.execute("PRAGMA user_version").fetchone()[0]
+
+
+def migrate(conn, dry_run: bool = False) -> list[tuple[int, str, list[str]]]:
+    """Bring the database up to SCHEMA_VERSION.
+
+    Returns (version, description, statements) for each migration that was
+    applied. With dry_run everything runs inside one transaction that is
+    rolled back, so the statements listed are exactly what a real run would
+    execute. A database newer than this copy of ccnotify is left alone.
+    """
+    if schema_version(conn) >= SCHEMA_VERSION:
+        return []
+    if conn.in_transaction:
+        conn.commit()
+    if not dry_run:
+        # WAL lets the dashboard read while hooks (or the collector) write
+        conn.execute("PRAGMA journal_mode=WAL")
+    done = []
+    if dry_run:
+        conn.execute("BEGIN IMMEDIATE")
+    try:
+        for version, desc, step in MIGRATIONS:
+            # IMMEDIATE takes the write lock, so concurrent hooks migrate one at a time
+            if not dry_run:
+                conn.execute("BEGIN IMMEDIATE")
+            if version <= schema_version(conn):
+                if not dry_run:
+                    conn.rollback()
+                continue
+            stmts = step(conn)
+            for sql in stmts:
+                conn.execute(sql)
+            conn.execute(f"PRAGMA user_version = {version}")
+            if not dry_run:
+                conn.commit()
+                logging.info(f"Migrated database to v{version}: {desc}")
+            done.append((version, desc, stmts))
+    finally:
+        if conn.in_transaction:
+            conn.rollback()
+    return done
+
+
+# ── LIVENESS ─────────────────────────────────────────────────
+
+
+def pid_alive(pid: int | None) -> bool:
+    if not pid:
+        return False
+    try:
+        os.kill(pid, 0)  # signal 0 = existence check, no side effects
+    except PermissionError:
+        return True  # exists, just not ours
+    except OSError:
+        return False
+    return True
+
+
+def reap(conn) -> dict:
+    """Close out sessions and agents whose Claude process went away without a Stop.
+
+    Runs from maintenance (collector timer, throttled hooks or `agent-top reap`),
+    never from the dashboard. Returns how many rows were touched.
+    """
+    open_pids = conn.execute(
+        "SELECT DISTINCT session_id, pid FROM prompt WHERE stopped_at IS NULL AND pid IS NOT NULL"
+    ).fetchall()
+    dead = sorted({sid for sid, pid in open_pids if not pid_alive(pid)})
+    sessions = 0
+    if dead:
+        sessions += conn.execute(
+            f"UPDATE prompt SET stopped_at = datetime('now') WHERE session_id IN ({','.join('?' * len(dead))}) AND stopped_at IS NULL",
+            dead,
+        ).rowcount
+        for sid in dead:
+            set_session_state(conn, sid, "crashed", "reap")
+
+    # Rows from before pids were recorded: time out after 2h of silence
+    sessions += conn.execute("""
+        UPDATE prompt SET stopped_at = datetime('now')
+        WHERE stopped_at IS NULL
+          AND pid IS NULL
+          AND created_at < datetime('now', '-2 hours')
+          AND (lastWaitUserAt IS NULL OR lastWaitUserAt < datetime('now', '-2 hours'))
+          AND session_id NOT IN (
+              SELECT DISTINCT session_id FROM tool_event
+              WHERE created_at > datetime('now', '-30 minutes')
+          )
+    """).rowcount
+
+    # Orphaned agents: every prompt of the parent session is closed. The 5 min
+    # grace period covers agents whose session row hasn't landed yet.
+    agents = conn.execute("""
+        UPDATE agent SET stopped_at = datetime('now')
+        WHERE stopped_at IS NULL
+          AND started_at < datetime('now', '-5 minutes')
+          AND session_id NOT IN (
+              SELECT DISTINCT session_id FROM prompt WHERE stopped_at IS NULL
+          )
+    """).rowcount
+
+    # Nobody is going to answer a session that is gone
+    conn.execute("""
+        UPDATE wait SET ended_at = datetime('now'),
+                        duration_ms = CAST((julianday('now') - julianday(started_at)) * 86400000 AS INTEGER)
+        WHERE ended_at IS NULL
+          AND session_id NOT IN (
+              SELECT DISTINCT session_id FROM prompt WHERE stopped_at IS NULL
+          )
+    """)
+    return {"sessions": sessions, "agents": agents}
+
+
+# ── WATCHDOG ─────────────────────────────────────────────────
+# A session that is working (not waiting for input) or a running agent with no
+# tool activity for `stall_after` seconds is flagged as stalled. Activity is a
+# tool call starting or finishing, an agent starting or stopping, or a prompt;
+# a Notification since then (a permission prompt, say) means it is waiting on you.
+# agent-top highlights stalls; maintenance marks each one (stalled_at) so it is
+# logged, and with "notify" announced, once.
+
+WATCHDOG_DEFAULTS = {
+    "stall_after": 600,  # seconds without activity; 0 disables the watchdog
+    "notify": False,     # send a "Stalled" notification (rules can match it either way)
+}
+# When a tool call finished, or started if it has no duration yet
+TOOL_DONE_SQL = "datetime(te.created_at, '+' || (COALESCE(te.duration_ms, 0) / 1000) || ' seconds')"
+
+
+def watchdog_config() -> dict:
+    cfg = dict(WATCHDOG_DEFAULTS)
+    user = load_config().get("watchdog", {})
+    if isinstance(user, dict):
+        cfg.update(user)
+    return cfg
+
+
+def find_stalls(conn, stall_after: float) -> list[dict]:
+    """Sessions and running agents idle for longer than stall_after seconds, most idle first."""
+    if not stall_after or stall_after <= 0:
+        return []
+    cutoff = f"-{float(stall_after)} seconds"
+    session_tools = f"SELECT MAX({TOOL_DONE_SQL}) FROM tool_event te WHERE te.session_id = {{0}}.session_id"
+    asking = "EXISTS (SELECT 1 FROM event e WHERE e.session_id = s.session_id AND e.event = 'Notification' AND e.created_at >= s.last_at)"
+    # Only a working session can stall; starting, idle and waiting ones are quiet on purpose
+    resting = ("EXISTS (SELECT 1 FROM session_state ss WHERE ss.session_id = p.session_id"
+               " AND ss.ended_at IS NULL AND ss.state != 'working')")
+    stalls = []
+    for row in conn.execute(
+        f"""SELECT * FROM (
+                SELECT p.id, p.session_id, p.cwd, p.pid, p.location, p.stalled_at,
+                       MAX(p.created_at,
+                           COALESCE(({session_tools.format("p")}), ''),
+                           COALESCE((SELECT MAX(MAX(a.started_at, COALESCE(a.stopped_at, ''))) FROM agent a
+                                     WHERE a.session_id = p.session_id), '')) AS last_at
+                FROM prompt p
+                WHERE p.id IN (SELECT MAX(id) FROM prompt WHERE stopped_at IS NULL GROUP BY session_id)
+                  AND p.lastWaitUserAt IS NULL
+                  AND p.prompt IS NOT NULL
+                  AND NOT {resting}
+            ) s WHERE last_at < datetime('now', ?) AND NOT ({asking})""",
+        (cutoff,),
+    ).fetchall():
+        prompt_id, sid, cwd, pid, location, stalled_at, last_at = row
+        if pid and not pid_alive(pid):
+            continue
+        stalls.append({"kind": "session", "session_id": sid, "agent_id": None, "agent_type": "", "cwd": cwd,
+                       "location": location, "last_at": last_at, "stalled_at": stalled_at, "row_id": prompt_id})
+    # Agents are judged by their own tool calls when the session attributes them, else by the session's
+    for row in conn.execute(
+        f"""SELECT * FROM (
+                SELECT a.id, a.session_id, a.agent_id, a.agent_type, a.cwd, a.stalled_at,
+                       (SELECT p.location FROM prompt p WHERE p.session_id = a.session_id ORDER BY p.id DESC LIMIT 1),
+                       MAX(a.started_at, COALESCE(CASE
+                           WHEN EXISTS (SELECT 1 FROM tool_event WHERE session_id = a.session_id AND agent_id IS NOT NULL)
+                           THEN (SELECT MAX({TOOL_DONE_SQL}) FROM tool_event te WHERE te.agent_id = a.agent_id)
+                           ELSE ({session_tools.format("a")})
+                       END, '')) AS last_at
+                FROM agent a
+                WHERE a.stopped_at IS NULL
+                  AND a.session_id IN (SELECT session_id FROM prompt WHERE stopped_at IS NULL)
+            ) s WHERE last_at < datetime('now', ?) AND NOT ({asking})""",
+        (cutoff,),
+    ).fetchall():
+        row_id, sid, aid, atype, cwd, stalled_at, location, last_at = row
+        stalls.append({"kind": "agent", "session_id": sid, "agent_id": aid, "agent_type": atype or "", "cwd": cwd,
+                       "location": location, "last_at": last_at, "stalled_at": stalled_at, "row_id": row_id})
+    now = datetime.now(timezone.utc)
+    for st in stalls:
+        try:
+            st["idle"] = (now - datetime.fromisoformat(st["last_at"][:19]).replace(tzinfo=timezone.utc)).total_seconds()
+        except ValueError:
+            st["idle"] = None
+    stalls.sort(key=lambda st: st["last_at"])
+    return stalls
+
+
+# ── RETENTION ────────────────────────────────────────────────
+# Per-table limits applied by maintenance. Rows that fall out are appended to
+# the daily aggregates (so STATS stays right for 7d/30d/all) and, if enabled,
+# to gzipped JSONL archives before being deleted. A missing or null limit
+# means "keep".
+
+RETENTION_DEFAULTS = {
+    "tool_event": {"max_age_days": 7, "max_per_session": 2000, "max_rows": None, "max_mb": 200},
+    "agent": {"max_age_days": None, "max_rows": None, "max_mb": None},
+    "prompt": {"max_age_days": None, "max_rows": None, "max_mb": None},
+    "notification": {"max_age_days": 30, "max_rows": None, "max_mb": None},
+    "event": {"max_age_days": 30, "max_rows": None, "max_mb": 500},
+    "file_touch": {"max_age_days": 90, "max_rows": None, "max_mb": None},
+    "conflict": {"max_age_days": 30, "max_rows": None, "max_mb": None},
+    "file_diff": {"max_age_days": 30, "max_rows": None, "max_mb": 200},
+    "wait": {"max_age_days": 90, "max_rows": None, "max_mb": None},
+    "session_state": {"max_age_days": 90, "max_rows": None, "max_mb": None},
+    "archive": False,
+    "archive_dir": os.path.join(SCRIPT_DIR, "archive"),
+}
+
+# Order matters: tool events are rolled up while their session's prompt rows
+# still exist to supply the project cwd.
+RETENTION_TABLES = {
+    # table: (timestamp column, only prune rows matching)
+    "tool_event": ("created_at", "1"),
+    "agent": ("started_at", "stopped_at IS NOT NULL"),
+    "prompt": ("created_at", "stopped_at IS NOT NULL"),
+    "notification": ("created_at", "status != 'held'"),
+    "event": ("created_at", "1"),
+    "file_touch": ("created_at", "1"),
+    "conflict": ("last_seen_at", "1"),
+    "file_diff": ("created_at", "1"),
+    "wait": ("started_at", "ended_at IS NOT NULL"),
+    "session_state": ("started_at", "ended_at IS NOT NULL"),
+}
+RETENTION_LIMITS = ("max_age_days", "max_per_session", "max_rows", "max_mb")
+# conflict rows name two sessions, so there is no single one to cap per
+NO_SESSION_TABLES = ("conflict",)
+
+
+def retention_config() -> dict:
+    cfg = {k: (dict(v) if isinstance(v, dict) else v) for k, v in RETENTION_DEFAULTS.items()}
+    user = load_config().get("retention", {})
+    if isinstance(user, dict):
+        for key, val in user.items():
+            if isinstance(cfg.get(key), dict) and isinstance(val, dict):
+                cfg[key].update(val)
+            else:
+                cfg[key] = val
+    for table, policy in cfg.items():
+        if not isinstance(policy, dict):
+            continue
+        if table not in RETENTION_TABLES:
+            logging.warning(f"Retention: unknown table {table!r} ignored")
+            continue
+        for key in list(policy):
+            if key not in RETENTION_LIMITS or (key == "max_per_session" and table in NO_SESSION_TABLES):
+                logging.warning(f"Retention: {key!r} does not apply to {table}, ignored")
+                del policy[key]
+    cfg["archive_dir"] = os.path.expanduser(cfg["archive_dir"])
+    return cfg
+
+
+def _table_mb(conn, table: str) -> float | None:
+    """On-disk size of a table and its indexes, or None without the dbstat module."""
+    try:
+        row = conn.execute(
+            "SELECT SUM(pgsize) FROM dbstat WHERE name = ? OR name IN "
+            "(SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = ?)",
+            (table, table),
+        ).fetchone()
+    except sqlite3.OperationalError:
+        return None
+    return (row[0] or 0) / 1e6
+
+
+def _select_expired(conn, table: str, policy: dict) -> None:
+    """Fill temp._expired with the ids of `table` that break its policy."""
+    ts_col, where = RETENTION_TABLES[table]
+    live = f"SELECT id FROM {table} WHERE {where}"
+    add = "INSERT OR IGNORE INTO temp._expired (id) "
+    if policy.get("max_age_days"):
+        conn.execute(
+            add + live + f" AND {ts_col} < datetime('now', ?)",
+            (f"-{float(policy['max_age_days'])} days",),
+        )
+    if policy.get("max_per_session"):
+        conn.execute(
+            add + f"""SELECT id FROM (
+                SELECT id, ROW_NUMBER() OVER (PARTITION BY session_id ORDER BY id DESC) AS n
+                FROM {table} WHERE {where}
+            ) WHERE n > ?""",
+            (int(policy["max_per_session"]),),
+        )
+    if policy.get("max_rows"):
+        conn.execute(add + live + " ORDER BY id DESC LIMIT -1 OFFSET ?", (int(policy["max_rows"]),))
+    if policy.get("max_mb"):
+        size = _table_mb(conn, table)
+        if size and size > policy["max_mb"]:
+            # Drop the oldest share of rows that should bring the table under its cap
+            total = conn.execute(f"SELECT COUNT(*) FROM {table}").fetchone()[0]
+            excess = int(total * (1 - policy["max_mb"] / size)) + 1
+            conn.execute(add + live + " ORDER BY id ASC LIMIT ?", (excess,))
+
+
+def _rollup(conn, table: str) -> None:
+    if table == "tool_event":
+        conn.execute("""
+            INSERT INTO tool_daily (day, tool_name, cwd, calls, errors, duration_ms)
+            SELECT date(te.created_at), te.tool_name,
+                   COALESCE((SELECT p.cwd FROM prompt p WHERE p.session_id = te.session_id ORDER BY p.id LIMIT 1), te.cwd, ''),
+                   COUNT(*), SUM(COALESCE(te.is_error, 0)), SUM(COALESCE(te.duration_ms, 0))
+            FROM tool_event te WHERE te.id IN (SELECT id FROM temp._expired)
+            GROUP BY 1, 2, 3
+            ON CONFLICT (day, tool_name, cwd) DO UPDATE SET
+                calls = calls + excluded.calls,
+                errors = errors + excluded.errors,
+                duration_ms = duration_ms + excluded.duration_ms
+        """)
+    elif table == "agent":
+        conn.execute("""
+            INSERT INTO agent_daily (day, agent_type, cwd, runs)
+            SELECT date(started_at), agent_type, COALESCE(cwd, ''), COUNT(*)
+            FROM agent WHERE id IN (SELECT id FROM temp._expired) AND agent_type != ''
+            GROUP BY 1, 2, 3
+            ON CONFLICT (day, agent_type, cwd) DO UPDATE SET runs = runs + excluded.runs
+        """)
+
+
+def _archive(conn, table: str, archive_dir: str) -> None:
+    """Append the expiring rows to <archive_dir>/<table>-<YYYY-MM-DD>.jsonl.gz."""
+    cur = conn.execute(f"SELECT * FROM {table} WHERE id IN (SELECT id FROM temp._expired) ORDER BY id")
+    cols = [d[0] for d in cur.description]
+    rows = cur.fetchall()
+    if not rows:
+        return
+    os.makedirs(archive_dir, exist_ok=True)
+    day = datetime.now(timezone.utc).strftime("%Y-%m-%d")
+    # gzip members concatenate, so appending keeps the file readable with zcat
+    with gzip.open(os.path.join(archive_dir, f"{table}-{day}.jsonl.gz"), "at", encoding="utf-8") as f:
+        for row in rows:
+            f.write(json.dumps(dict(zip(cols, row)), default=str) + "\n")
+
+
+def prune(conn, cfg: dict | None = None) -> dict:
+    """Apply retention to every table; returns rows removed per table."""
+    cfg = cfg or retention_config()
+    removed = {}
+    # One savepoint, so a row is never both rolled up and kept (or the reverse)
+    conn.execute("SAVEPOINT prune")
+    conn.execute("CREATE TEMP TABLE IF NOT EXISTS _expired (id INTEGER PRIMARY KEY)")
+    try:
+        for table in RETENTION_TABLES:
+            policy = cfg.get(table) or {}
+            conn.execute("DELETE FROM temp._expired")
+            _select_expired(conn, table, policy)
+            if not conn.execute("SELECT 1 FROM temp._expired LIMIT 1").fetchone():
+                continue
+            if cfg.get("archive"):
+                _archive(conn, table, cfg["archive_dir"])
+            _rollup(conn, table)
+            removed[table] = conn.execute(
+                f"DELETE FROM {table} WHERE id IN (SELECT id FROM temp._expired)"
+            ).rowcount
+        conn.execute("DROP TABLE temp._expired")
+    except Exception:
+        conn.execute("ROLLBACK TO prune")
+        raise
+    finally:
+        conn.execute("RELEASE prune")
+    return removed
+
+
+# ── TOKEN USAGE ──────────────────────────────────────────────
+# Claude Code writes every assistant message, with its `usage` block, to the
+# session transcript (and subagents to their own). We read each transcript
+# incrementally from a stored byte offset and keep one row per message id —
+# streamed messages are written several times, the last copy wins.
+
+# USD per million tokens. Matched as a substring of the model id, longest key
+# first; `"prices"` in config.json adds to or overrides these.
+PRICE_DEFAULTS: dict[str, dict[str, float]] = {
+    "opus-4-5": {"input": 5, "output": 25, "cache_write": 6.25, "cache_read": 0.50},
+    "opus": {"input": 15, "output": 75, "cache_write": 18.75, "cache_read": 1.50},
+    "sonnet": {"input": 3, "output": 15, "cache_write": 3.75, "cache_read": 0.30},
+    "haiku-4-5": {"input": 1, "output": 5, "cache_write": 1.25, "cache_read": 0.10},
+    "3-5-haiku": {"input": 0.80, "output": 4, "cache_write": 1, "cache_read": 0.08},
+    "haiku": {"input": 0.25, "output": 1.25, "cache_write": 0.30, "cache_read": 0.03},
+}
+
+_prices: list[tuple[str, dict]] | None = None
+
 
+def price_for(model: str | None) -> dict | None:
+    global _prices
+    if _prices is None:
+        table = dict(PRICE_DEFAULTS)
+        user = load_config().get("prices", {})
+        if isinstance(user, dict):
+            table.update({k: v for k, v in user.items() if isinstance(v, dict)})
+        _prices = sorted(table.items(), key=lambda kv: -len(kv[0]))
+    for key, price in _prices:
+        if model and key in model:
+            return price
+    return None
+
+
+def usage_cost(model: str | None, tokens: dict) -> float:
+    price = price_for(model)
+    if not price:
+        return 0.0
+    return sum(tokens[k] * float(price.get(k, 0)) for k in tokens) / 1e6
 
-def send_notification(title: str, subtitle: str, message: str = "",
-                      sound_key: str = "task_complete", cwd: str | None = None) -> None:
-    """Send macOS notification via terminal-notifier with click-to-focus iTerm."""
-    play_sound(sound_key)
 
+def _transcript_ts(ts: str | None) -> str | None:
+    """ISO-8601 transcript timestamp -> SQLite's UTC 'YYYY-MM-DD HH:MM:SS'."""
     try:
-        cmd = [
-            "terminal-notifier",
-            "-title", title,
-            "-subtitle", subtitle,
-        ]
-        if message:
-            cmd.extend(["-message", message])
-        # Use Finder as sender so macOS never suppresses the banner
-        # (banners are hidden when the sender app is focused — Finder is never focused)
-        cmd.extend(["-sender", "com.apple.Finder"])
-        # Click notification -> activate iTerm
-        cmd.extend(["-activate", "com.googlecode.iterm2"])
-        cmd.extend(["-ignoreDnD"])
-        if cwd:
-            cmd.extend(["-group", f"claude-{os.path.basename(cwd)}"])
+        dt = datetime.fromisoformat(str(ts).replace("Z", "+00:00"))
+    except ValueError:
+        return None
+    if dt.tzinfo:
+        dt = dt.astimezone(timezone.utc)
+    return dt.strftime("%Y-%m-%d %H:%M:%S")
 
-        subprocess.run(cmd, check=False, capture_output=True, timeout=5)
-        logging.info(f"Notified: {title} | {subtitle} | {message}")
-    except FileNotFoundError:
-        # Fallback to osascript if terminal-notifier missing
+
+def track_transcript(conn, path: str, session_id: str, agent_id: str | None = None) -> None:
+    conn.execute(
+        """INSERT INTO transcript (path, session_id, agent_id) VALUES (?, ?, ?)
+           ON CONFLICT(path) DO UPDATE SET agent_id = COALESCE(excluded.agent_id, agent_id)""",
+        (path, session_id, agent_id),
+    )
+
+
+def scan_transcript(conn, path: str) -> int:
+    """Read new lines of a tracked transcript into `usage`. Returns messages seen."""
+    row = conn.execute("SELECT session_id, agent_id, offset FROM transcript WHERE path = ?", (path,)).fetchone()
+    if not row:
+        return 0
+    session_id, agent_id, offset = row
+    try:
+        size = os.path.getsize(path)
+        if size < offset:
+            offset = 0  # rewritten (compaction) — start over, ids dedupe
+        if size == offset:
+            return 0
+        with open(path, "rb") as f:
+            f.seek(offset)
+            chunk = f.read()
+    except OSError:
+        return 0
+    # Only whole lines; a half-written one is picked up next time
+    end = chunk.rfind(b"\n") + 1
+    seen = 0
+    for line in chunk[:end].splitlines():
         try:
-            safe_title = title.replace('"', '\\"')
-            safe_sub = subtitle.replace('"', '\\"')
-            script = f'display notification "{safe_sub}" with title "{safe_title}"'
-            subprocess.run(["osascript", "-e", script], check=False,
-                           capture_output=True, timeout=5)
-        except Exception:
-            pass
-    except Exception as e:
-        logging.error(f"Notification error: {e}")
+            entry = json.loads(line)
+        except ValueError:
+            continue
+        if not isinstance(entry, dict) or entry.get("type") != "assistant":
+            continue
+        msg = entry.get("message")
+        if not isinstance(msg, dict) or not isinstance(msg.get("usage"), dict):
+            continue
+        model = msg.get("model")
+        if model == "<synthetic>":
+            continue
+        u = msg["usage"]
+        tokens = {
+            "input": int(u.get("input_tokens") or 0),
+            "output": int(u.get("output_tokens") or 0),
+            "cache_write": int(u.get("cache_creation_input_tokens") or 0),
+            "cache_read": int(u.get("cache_read_input_tokens") or 0),
+        }
+        ts = _transcript_ts(entry.get("timestamp"))
+        conn.execute(
+            """INSERT INTO usage (message_id, session_id, agent_id, prompt_id, model, input_tokens, output_tokens,
+                                  cache_write_tokens, cache_read_tokens, cost_usd, created_at)
+               VALUES (?, ?, ?, (SELECT id FROM prompt WHERE session_id = ? AND created_at <= COALESCE(?, CURRENT_TIMESTAMP)
+                                 ORDER BY id DESC LIMIT 1), ?, ?, ?, ?, ?, ?, COALESCE(?, CURRENT_TIMESTAMP))
+               ON CONFLICT(message_id) DO UPDATE SET
+                   agent_id = COALESCE(excluded.agent_id, agent_id),
+                   input_tokens = excluded.input_tokens, output_tokens = excluded.output_tokens,
+                   cache_write_tokens = excluded.cache_write_tokens, cache_read_tokens = excluded.cache_read_tokens,
+                   cost_usd = excluded.cost_usd""",
+            (msg.get("id") or entry.get("uuid"), session_id, agent_id, session_id, ts, model,
+             tokens["input"], tokens["output"], tokens["cache_write"], tokens["cache_read"],
+             usage_cost(model, tokens), ts),
+        )
+        seen += 1
+    conn.execute(
+        "UPDATE transcript SET offset = ?, updated_at = CURRENT_TIMESTAMP WHERE path = ?",
+        (offset + end, path),
+    )
+    return seen
+
+
+def _agent_transcript_candidates(session_transcript: str, agent_id: str) -> list[str]:
+    base = os.path.dirname(session_transcript)
+    session = os.path.splitext(os.path.basename(session_transcript))[0]
+    return [
+        os.path.join(base, session, "subagents", f"agent-{agent_id}.jsonl"),
+        os.path.join(base, f"agent-{agent_id}.jsonl"),
+    ]
+
+
+def transcript_agent_id(path: str | None) -> str | None:
+    """Agent id from a subagent transcript path (`.../agent-<id>.jsonl`), None for a session transcript."""
+    m = re.search(r"(?:^|/)agent-([^/]+)\.jsonl$", path or "")
+    return m.group(1) if m else None
+
+
+def scan_usage(conn) -> int:
+    """Catch up on every tracked transcript, finding running agents' files as they appear."""
+    for agent_id, path in conn.execute(
+        """SELECT a.agent_id, t.path FROM agent a
+           JOIN transcript t ON t.session_id = a.session_id AND t.agent_id IS NULL
+           WHERE a.stopped_at IS NULL
+             AND NOT EXISTS (SELECT 1 FROM transcript x WHERE x.agent_id = a.agent_id)"""
+    ).fetchall():
+        for candidate in _agent_transcript_candidates(path, agent_id):
+            if os.path.exists(candidate):
+                session_id = conn.execute("SELECT session_id FROM agent WHERE agent_id = ?", (agent_id,)).fetchone()[0]
+                track_transcript(conn, candidate, session_id, agent_id)
+                break
+    seen = 0
+    for (path,) in conn.execute("SELECT path FROM transcript").fetchall():
+        seen += scan_transcript(conn, path)
+    return seen
+
+
+# Tables derived from the event log, with the column `rebuild` uses to tell
+# which rows the log covers.
+PROJECTIONS = {
+    "prompt": "created_at",
+    "agent": "started_at",
+    "tool_event": "created_at",
+    "team_session": "last_seen_at",
+    "compaction": "created_at",
+    "file_touch": "created_at",
+    "conflict": "created_at",
+    "file_diff": "created_at",
+    "wait": "started_at",
+    "session_state": "started_at",
+}
+
+# Tools whose file_path goes into file_touch, and the op recorded
+FILE_TOOL_OPS = {"Read": "read", "Write": "write", "Edit": "edit", "MultiEdit": "edit", "NotebookEdit": "edit"}
+
+# Notification subtitles that mean the session is blocked on the user, and the wait kind stored
+WAIT_KINDS = {"Waiting for input": "input", "Permission required": "permission", "Action required": "action"}
+
+# Hook events with a handler; anything else is only kept in the event log,
+# and agent-top shows it as a plain timeline entry.
+HANDLED_EVENTS = (
+    "SessionStart", "SessionEnd", "UserPromptSubmit", "Stop", "SubagentStart", "SubagentStop",
+    "Notification", "PreToolUse", "PostToolUse", "PostToolUseFailure", "TeammateIdle", "TaskCompleted",
+    "PreCompact",
+)
+
+
+class _BatchConnection:
+    """Long-lived collector connection: handler commits are deferred to the end of the batch."""
+
+    def __init__(self, conn: sqlite3.Connection):
+        self._conn = conn
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *exc):
+        return False
+
+    def commit(self):
+        pass
+
+    def __getattr__(self, name):
+        return getattr(self._conn, name)
 
 
 class ClaudePromptTracker:
-    def __init__(self):
+    def __init__(self, db_path: str | None = None, log: bool = True):
         script_dir = os.path.dirname(os.path.abspath(__file__))
-        self.db_path = os.path.join(script_dir, "ccnotify.db")
-        self.setup_logging()
+        self.db_path = db_path or os.path.join(script_dir, "ccnotify.db")
+        # Process that fired the hook — the collector overrides these per event
+        self.hook_pid = os.getppid()
+        self.hook_env = os.environ
+        self._batch: _BatchConnection | None = None
+        # Collector batch only: notifications, sounds and commands to run once it commits
+        self._outbox: list[tuple] | None = None
+        # Set while `rebuild` replays the event log: the event's timestamp and recorded pane
+        self.replaying: str | None = None
+        self.event_location: dict | None = None
+        self.event_git: dict | None = None
+        self.event_session: str = ""
+        # Row the event was logged as, and the secrets its handler masked (as logged, on rebuild)
+        self.event_id: int | None = None
+        self.event_redactions = 0
+        if log:
+            self.setup_logging()
         self.init_database()
 
+    def _connect(self):
+        """Connection for one handler: the collector's batch, or a fresh one in hook mode."""
+        return self._batch or sqlite3.connect(self.db_path)
+
     def setup_logging(self):
         script_dir = os.path.dirname(os.path.abspath(__file__))
         log_path = os.path.join(script_dir, "ccnotify.log")
@@ -174,74 +1967,149 @@ class ClaudePromptTracker:
 
     def init_database(self):
         with sqlite3.connect(self.db_path) as conn:
-            conn.execute("""
-                CREATE TABLE IF NOT EXISTS prompt (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    session_id TEXT NOT NULL,
-                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                    prompt TEXT,
-                    cwd TEXT,
-                    seq INTEGER,
-                    stoped_at DATETIME,
-                    lastWaitUserAt DATETIME,
-                    pid INTEGER
-                )
-            """)
-            conn.execute("""
-                CREATE TRIGGER IF NOT EXISTS auto_increment_seq
-                AFTER INSERT ON prompt
-                FOR EACH ROW
-                BEGIN
-                    UPDATE prompt SET seq = (
-                        SELECT COALESCE(MAX(seq), 0) + 1
-                        FROM prompt WHERE session_id = NEW.session_id
-                    ) WHERE id = NEW.id;
-                END
-            """)
-            conn.execute("""
-                CREATE TABLE IF NOT EXISTS agent (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    agent_id TEXT NOT NULL UNIQUE,
-                    agent_type TEXT NOT NULL,
-                    session_id TEXT NOT NULL,
-                    cwd TEXT,
-                    started_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                    stopped_at DATETIME,
-                    transcript_path TEXT
-                )
-            """)
-            conn.execute("""
-                CREATE TABLE IF NOT EXISTS tool_event (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    session_id TEXT NOT NULL,
-                    tool_name TEXT NOT NULL,
-                    tool_label TEXT NOT NULL,
-                    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                    tool_input TEXT,
-                    tool_response TEXT,
-                    tool_use_id TEXT,
-                    duration_ms INTEGER,
-                    cwd TEXT
-                )
-            """)
-            conn.execute("""
-                CREATE INDEX IF NOT EXISTS idx_tool_event_session
-                    ON tool_event (session_id, created_at DESC)
-            """)
-            conn.execute("""
-                CREATE TABLE IF NOT EXISTS team_session (
-                    session_id TEXT PRIMARY KEY,
-                    team_name TEXT NOT NULL,
-                    teammate_name TEXT NOT NULL,
-                    last_seen_at DATETIME DEFAULT CURRENT_TIMESTAMP
-                )
-            """)
-            # Prune tool events older than 24h
-            conn.execute("""
-                DELETE FROM tool_event
-                WHERE created_at < datetime('now', '-24 hours')
-            """)
+            migrate(conn)
+
+    def maintenance(self) -> None:
+        """Periodic cleanup — run by the collector's timer, or throttled from hooks."""
+        with self._connect() as conn:
+            reaped = reap(conn)
+            scan_usage(conn)
+            removed = prune(conn)
+            conn.execute("DELETE FROM notify_queue WHERE sent_at < ?", (time.time() - 86400,))
             conn.commit()
+        pruned = ", ".join(f"{n} {t}" for t, n in removed.items()) or "nothing"
+        logging.info(f"Maintenance done (reaped {reaped['sessions']} prompt rows, {reaped['agents']} agents; pruned {pruned})")
+        self.watchdog()
+
+    def watchdog(self) -> None:
+        """Mark sessions and agents that went quiet, and announce each stall once."""
+        cfg = watchdog_config()
+        with self._connect() as conn:
+            # Flagged already unless there has been activity since
+            new = [st for st in find_stalls(conn, float(cfg.get("stall_after") or 0))
+                   if not st["stalled_at"] or st["stalled_at"] < st["last_at"]]
+            for st in new:
+                conn.execute(f"UPDATE {'agent' if st['kind'] == 'agent' else 'prompt'} SET stalled_at = ? WHERE id = ?",
+                             (self._now(), st["row_id"]))
+            conn.commit()
+        for st in new:
+            who = f"{st['agent_type'] or 'agent'} {st['agent_id'][:8]}" if st["kind"] == "agent" else "session"
+            idle = fmt_duration(st["idle"])
+            logging.info(f"Watchdog: {who} stalled for {idle} session={st['session_id']}")
+            location = json.loads(st["location"]) if st["location"] else {}
+            data = {"session_id": st["session_id"], "cwd": st["cwd"] or "", "agent_type": st["agent_type"]}
+            self._emit("Stalled", data, f"{who[0].upper()}{who[1:]} stalled: no activity for {idle}", "error",
+                       notify=bool(cfg.get("notify")), duration=st["idle"],
+                       title=location.get("window") or os.path.basename((st["cwd"] or "").rstrip("/")),
+                       loc=location.get("label", ""))
+
+    def maybe_maintenance(self, interval: float) -> None:
+        """Run maintenance from a hook at most once per interval (stamp file mtime)."""
+        stamp = os.path.join(os.path.dirname(self.db_path), ".maintenance")
+        try:
+            if time.time() - os.path.getmtime(stamp) < interval:
+                return
+        except OSError:
+            pass
+        try:
+            with open(stamp, "w"):
+                pass
+        except OSError:
+            return
+        self.maintenance()
+
+    def rebuild(self) -> dict:
+        """Regenerate the projection tables from the event log.
+
+        Rows from before the log's first event are left alone; everything since
+        is deleted and replayed through the handlers with the logged timestamps,
+        pids and panes. Notifications, sounds and transcript reads are skipped.
+        """
+        conn = sqlite3.connect(self.db_path, isolation_level=None, timeout=30)
+        replayed = failed = 0
+        try:
+            first = conn.execute("SELECT MIN(created_at) FROM event").fetchone()[0]
+            if first is None:
+                return {"since": None, "replayed": 0, "failed": 0}
+            first = first[:19]  # projection rows have whole seconds
+            conn.execute("BEGIN IMMEDIATE")
+            for table, ts_col in PROJECTIONS.items():
+                conn.execute(f"DELETE FROM {table} WHERE {ts_col} >= ?", (first,))
+            # Replayed rows past their retention are rolled up again by prune() below
+            conn.execute("DELETE FROM tool_daily WHERE day >= date(?)", (first,))
+            conn.execute("DELETE FROM agent_daily WHERE day >= date(?)", (first,))
+            self._batch = _BatchConnection(conn)
+            for event, payload, pid, env, location, git, redactions, created_at in conn.execute(
+                "SELECT event, payload, pid, env, location, git, redactions, created_at FROM event ORDER BY id"
+            ):
+                self.replaying = created_at
+                self.event_redactions = redactions or 0
+                self.hook_pid = pid or 0
+                self.hook_env = json.loads(env or "{}")
+                self.event_location = json.loads(location) if location else {}
+                self.event_git = json.loads(git) if git else {}
+                conn.execute("SAVEPOINT replay")
+                try:
+                    self.dispatch(event, json.loads(payload))
+                    replayed += 1
+                except Exception as e:
+                    conn.execute("ROLLBACK TO replay")
+                    failed += 1
+                    logging.error(f"Rebuild: {event} at {created_at} failed: {e}")
+                conn.execute("RELEASE replay")
+            self.replaying = None
+            # Sessions whose process is gone end at their last logged event, not now
+            dead = [sid for sid, pid in conn.execute(
+                "SELECT DISTINCT session_id, pid FROM prompt WHERE stopped_at IS NULL AND pid IS NOT NULL"
+            ).fetchall() if not pid_alive(pid)]
+            for sid in dead:
+                last = conn.execute("SELECT MAX(created_at) FROM event WHERE session_id = ?", (sid,)).fetchone()[0]
+                conn.execute("UPDATE prompt SET stopped_at = ? WHERE session_id = ? AND stopped_at IS NULL", (last, sid))
+                conn.execute("UPDATE agent SET stopped_at = ? WHERE session_id = ? AND stopped_at IS NULL", (last, sid))
+                set_session_state(conn, sid, "crashed", "rebuild", last[:19] if last else None)
+            reap(conn)
+            # usage rows point at prompt ids, which were just renumbered
+            conn.execute(
+                """UPDATE usage SET prompt_id = (
+                       SELECT p.id FROM prompt p WHERE p.session_id = usage.session_id AND p.created_at <= usage.created_at
+                       ORDER BY p.id DESC LIMIT 1)
+                   WHERE created_at >= ?""",
+                (first,),
+            )
+            prune(conn)
+            conn.execute("COMMIT")
+        except Exception:
+            if conn.in_transaction:
+                conn.execute("ROLLBACK")
+            raise
+        finally:
+            self._batch = None
+            self.replaying = None
+            self.event_location = None
+            self.event_git = None
+            self.event_redactions = 0
+            conn.close()
+        logging.info(f"Rebuilt from {replayed} event(s) since {first} ({failed} failed)")
+        return {"since": first, "replayed": replayed, "failed": failed}
+
+    @staticmethod
+    def _file_touches(tool_name: str, tool_input: dict, cwd: str) -> list[tuple[str, str]]:
+        """(absolute path, op) pairs a tool call touches: read, write, edit, or search for Glob/Grep paths."""
+        if not isinstance(tool_input, dict):
+            return []
+        if tool_name in FILE_TOOL_OPS:
+            path = tool_input.get("file_path") or tool_input.get("notebook_path")
+            op = FILE_TOOL_OPS[tool_name]
+        elif tool_name in ("Glob", "Grep"):
+            path, op = tool_input.get("path"), "search"
+        else:
+            return []
+        if not path or not isinstance(path, str):
+            return []
+        path = os.path.expanduser(path)
+        if not os.path.isabs(path) and cwd:
+            path = os.path.join(cwd, path)
+        return [(os.path.normpath(path), op)]
 
     @staticmethod
     def _extract_tool_label(tool_name: str, tool_input: dict) -> str:
@@ -279,88 +2147,308 @@ class ClaudePromptTracker:
         tool_input = data.get("tool_input", {})
         if not session_id or not tool_name:
             return
-        label = self._extract_tool_label(tool_name, tool_input)
+        label, n_label = redact_text(self._extract_tool_label(tool_name, tool_input))
+        input_str, n_input = redact_json(tool_input)
         tool_use_id = data.get("tool_use_id", "")
         cwd = data.get("cwd", "")
-        with sqlite3.connect(self.db_path) as conn:
+        # Writes and edits are recorded at PostToolUse, once they went through
+        touches = [t for t in self._file_touches(tool_name, tool_input, cwd) if t[1] not in WRITE_OPS]
+        with self._connect() as conn:
             conn.execute(
-                "INSERT INTO tool_event (session_id, tool_name, tool_label, tool_input, tool_use_id, cwd) VALUES (?, ?, ?, ?, ?, ?)",
-                (session_id, tool_name, label, json.dumps(tool_input, default=str)[:4000], tool_use_id, cwd),
+                """INSERT INTO tool_event (session_id, tool_name, tool_label, tool_input, tool_use_id, cwd, redactions,
+                                           agent_id, created_at)
+                   VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)""",
+                (session_id, tool_name, label, input_str, tool_use_id, cwd, self._redactions(n_label + n_input),
+                 self._tool_agent_id(data), self._now()),
             )
-            # Lazy ring buffer: prune when count > 100, keep last 50
-            count = conn.execute(
-                "SELECT COUNT(*) FROM tool_event WHERE session_id = ?",
-                (session_id,),
-            ).fetchone()[0]
-            if count > 100:
-                conn.execute(
-                    """DELETE FROM tool_event WHERE session_id = ? AND id NOT IN (
-                        SELECT id FROM tool_event WHERE session_id = ?
-                        ORDER BY created_at DESC LIMIT 50
-                    )""",
-                    (session_id, session_id),
-                )
+            self._record_touches(conn, data, touches)
             conn.commit()
         logging.info(f"Tool event: {tool_name} -> {label} session={session_id}")
+        self._emit("PreToolUse", data, f"{tool_name}: {label}", notify=False)
+
+    def _record_touches(self, conn, data: dict, touches: list[tuple[str, str]]) -> list[tuple[str, tuple[str, str]]]:
+        """Insert file_touch rows for a tool call; returns (path, other writer) for each new conflict."""
+        session_id = data.get("session_id", "")
+        agent_id = self._tool_agent_id(data)
+        conn.executemany(
+            "INSERT INTO file_touch (session_id, agent_id, tool_use_id, path, op, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+            [(session_id, agent_id, data.get("tool_use_id", ""), path, op, self._now()) for path, op in touches],
+        )
+        return [(path, other) for path, op in touches if op in WRITE_OPS
+                for other in self._record_conflicts(conn, session_id, agent_id or "", path)]
+
+    def _emit_conflicts(self, data: dict, conflicts: list[tuple[str, tuple[str, str]]]) -> None:
+        """Raise a "Conflict" notification for each new pair _record_touches found."""
+        session_id = data.get("session_id", "")
+        for path, (other_sid, other_agent) in conflicts:
+            who = f"agent {other_agent[:8]}" if other_sid == session_id else f"session {other_sid[:8]}"
+            logging.info(f"Conflict: {path} edited by {session_id} and {other_sid}/{other_agent}")
+            self._emit("Conflict", data, f"Conflict: {os.path.basename(path)} also edited by {who}", "error",
+                       path=path, message=path)
+
+    def _record_conflicts(self, conn, session_id: str, agent_id: str, path: str) -> list[tuple[str, str]]:
+        """Upsert conflict rows for other live writers of `path` within the window; returns the new ones."""
+        window = float(conflict_config().get("window") or 0)
+        if window <= 0:
+            return []
+        now = self._now()
+        cutoff = (datetime.fromisoformat(now) - timedelta(seconds=window)).strftime("%Y-%m-%d %H:%M:%S")
+        writers = conn.execute(
+            f"""SELECT DISTINCT session_id, COALESCE(agent_id, '') FROM file_touch
+                WHERE path = ? AND op IN ({",".join("?" * len(WRITE_OPS))}) AND created_at >= ?
+                  AND NOT (session_id = ? AND COALESCE(agent_id, '') = ?)""",
+            (path, *WRITE_OPS, cutoff, session_id, agent_id),
+        ).fetchall()
+        new = []
+        for other in writers:
+            # A session's main thread and its own subagents take turns; only parallel subagents race
+            if other[0] == session_id and not (agent_id and other[1]):
+                continue
+            if not self._writer_live(conn, *other):
+                continue
+            a, b = sorted([(session_id, agent_id), tuple(other)])
+            row = conn.execute(
+                "SELECT last_seen_at FROM conflict WHERE path = ? AND session_a = ? AND agent_a = ? AND session_b = ? AND agent_b = ?",
+                (path, *a, *b),
+            ).fetchone()
+            conn.execute(
+                """INSERT INTO conflict (path, session_a, agent_a, session_b, agent_b, created_at, last_seen_at)
+                   VALUES (?, ?, ?, ?, ?, ?, ?)
+                   ON CONFLICT (path, session_a, agent_a, session_b, agent_b) DO UPDATE SET last_seen_at = excluded.last_seen_at""",
+                (path, *a, *b, now, now),
+            )
+            # Notify once per pair and path, and again if it flares up after going quiet
+            if row is None or row[0] < cutoff:
+                new.append(tuple(other))
+        return new
+
+    def _writer_live(self, conn, session_id: str, agent_id: str) -> bool:
+        """Whether a session (or one of its agents) is still running."""
+        if agent_id:
+            return conn.execute(
+                "SELECT 1 FROM agent WHERE session_id = ? AND agent_id = ? AND stopped_at IS NULL", (session_id, agent_id)
+            ).fetchone() is not None
+        pids = [pid for (pid,) in conn.execute(
+            "SELECT pid FROM prompt WHERE session_id = ? AND stopped_at IS NULL", (session_id,)
+        ).fetchall()]
+        # Liveness at the time of a replayed event is whatever the rows said then
+        return bool(pids) and (bool(self.replaying) or any(pid is None or pid_alive(pid) for pid in pids))
+
+    @staticmethod
+    def _tool_agent_id(data: dict) -> str | None:
+        """Agent a tool hook fired in, when the payload or its transcript path says; None if it doesn't."""
+        return data.get("agent_id") or transcript_agent_id(data.get("transcript_path")) or None
+
+    @staticmethod
+    def _spawned_agent_id(tool_response) -> str | None:
+        """Agent id a Task call's response reports (`agentId`), if any."""
+        if isinstance(tool_response, dict):
+            return tool_response.get("agentId") or tool_response.get("agent_id") or None
+        return None
+
+    @staticmethod
+    def _response_error(tool_name: str, tool_response) -> str | None:
+        """Return an error message if a PostToolUse response describes a failure."""
+        if not isinstance(tool_response, dict):
+            return None
+        if tool_response.get("is_error") or tool_response.get("isError"):
+            err = tool_response.get("error") or tool_response.get("content") or "error"
+            return str(err)[:2000]
+        if tool_name == "Bash":
+            code = None
+            for key in ("exitCode", "exit_code", "returnCode", "return_code"):
+                if tool_response.get(key) not in (None, ""):
+                    code = tool_response[key]
+                    break
+            try:
+                code = int(code) if code is not None else 0
+            except (TypeError, ValueError):
+                code = 0
+            if code != 0:
+                stderr = str(tool_response.get("stderr") or "").strip()
+                return (f"exit {code}: {stderr}" if stderr else f"exit {code}")[:2000]
+            if tool_response.get("interrupted"):
+                return "interrupted"
+        return None
+
+    def _now(self) -> str:
+        """Timestamp for rows this event writes: now (UTC, as CURRENT_TIMESTAMP), or the logged time on rebuild."""
+        return (self.replaying or datetime.now(timezone.utc).strftime("%Y-%m-%d %H:%M:%S"))[:19]
+
+    def _elapsed_ms(self, created_at: str) -> int | None:
+        """Milliseconds between a row's created_at (UTC) and this event."""
+        try:
+            start = datetime.fromisoformat(created_at).replace(tzinfo=timezone.utc)
+            now = (datetime.fromisoformat(self.replaying).replace(tzinfo=timezone.utc) if self.replaying
+                   else datetime.now(timezone.utc))
+            return int((now - start).total_seconds() * 1000)
+        except Exception:
+            return None
 
     def handle_post_tool_use(self, data: dict) -> None:
         """Update the matching PreToolUse row with response and duration."""
         session_id = data.get("session_id", "")
         tool_use_id = data.get("tool_use_id", "")
+        tool_name = data.get("tool_name", "")
         tool_response = data.get("tool_response", {})
         if not session_id or not tool_use_id:
             return
-        response_str = json.dumps(tool_response, default=str)[:4000]
-        with sqlite3.connect(self.db_path) as conn:
+        response_str, redacted = redact_json(tool_response)
+        error = self._response_error(tool_name, tool_response)
+        if error:
+            error, n = redact_text(error)
+            redacted += n
+        elapsed = None
+        conflicts = []
+        with self._connect() as conn:
             # Find the matching PreToolUse row and compute duration
             row = conn.execute(
                 "SELECT id, created_at FROM tool_event WHERE tool_use_id = ? LIMIT 1",
                 (tool_use_id,),
             ).fetchone()
             if row:
-                try:
-                    start = datetime.fromisoformat(row[1])
-                    dur_ms = int((datetime.now() - start).total_seconds() * 1000)
-                except Exception:
-                    dur_ms = None
+                elapsed = self._elapsed_ms(row[1])
                 conn.execute(
-                    "UPDATE tool_event SET tool_response = ?, duration_ms = ? WHERE id = ?",
-                    (response_str, dur_ms, row[0]),
+                    """UPDATE tool_event SET tool_response = ?, duration_ms = ?, is_error = ?, error_message = ?,
+                              redactions = COALESCE(redactions, 0) + ? WHERE id = ?""",
+                    (response_str, elapsed, 1 if error else 0, error, self._redactions(redacted), row[0]),
                 )
-                conn.commit()
-        logging.info(f"PostToolUse: {tool_use_id} session={session_id}")
+            writes = [] if error else [t for t in self._file_touches(tool_name, data.get("tool_input", {}),
+                                                                     data.get("cwd", "")) if t[1] in WRITE_OPS]
+            conflicts = self._record_touches(conn, data, writes)
+            diff = build_diff(tool_name, data.get("tool_input", {}), tool_response, writes[0][0]) if writes else None
+            if diff:
+                conn.execute(
+                    """INSERT INTO file_diff (session_id, agent_id, tool_use_id, tool_name, path, diff, added, removed,
+                                              truncated, created_at)
+                       VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)""",
+                    (session_id, self._tool_agent_id(data), tool_use_id, tool_name, writes[0][0],
+                     diff[0], diff[1], diff[2], int(diff[3]), self._now()),
+                )
+            spawned = self._spawned_agent_id(tool_response) if tool_name == "Task" else None
+            if spawned:
+                # The Task call that started this agent; the tree uses it instead of guessing by time.
+                # Whoever made that call is the parent, if SubagentStart couldn't tell.
+                conn.execute(
+                    """UPDATE agent SET tool_use_id = ?,
+                              parent_agent_id = COALESCE(parent_agent_id, (SELECT agent_id FROM tool_event WHERE id = ?))
+                       WHERE agent_id = ?""",
+                    (tool_use_id, row[0] if row else None, spawned),
+                )
+            conn.commit()
+        logging.info(f"PostToolUse: {tool_use_id} session={session_id}" + (f" error={error!r}" if error else "")
+                     + (f" agent={spawned}" if spawned else ""))
+        self._emit("PostToolUse", data, f"Failed: {tool_name}" if error else f"{tool_name} done",
+                   "error" if error else "task_complete", notify=False,
+                   duration=elapsed / 1000 if elapsed is not None else None, message=error or "")
+        self._emit_conflicts(data, conflicts)
+
+    def handle_post_tool_use_failure(self, data: dict) -> None:
+        """Mark the matching PreToolUse row as failed with the error text and duration."""
+        session_id = data.get("session_id", "")
+        tool_use_id = data.get("tool_use_id", "")
+        tool_name = data.get("tool_name", "")
+        tool_input = data.get("tool_input", {})
+        if not session_id or not tool_name:
+            return
+        error = data.get("error") or data.get("error_message") or "failed"
+        if not isinstance(error, str):
+            error = json.dumps(error, default=str)
+        if data.get("is_interrupt"):
+            error = f"interrupted: {error}"
+        error, redacted = redact_text(error)
+        error = error[:2000]
+        elapsed = None
+        with self._connect() as conn:
+            row = None
+            if tool_use_id:
+                row = conn.execute(
+                    "SELECT id, created_at FROM tool_event WHERE tool_use_id = ? LIMIT 1",
+                    (tool_use_id,),
+                ).fetchone()
+            if row:
+                elapsed = self._elapsed_ms(row[1])
+                conn.execute(
+                    """UPDATE tool_event SET is_error = 1, error_message = ?, duration_ms = ?,
+                              redactions = COALESCE(redactions, 0) + ? WHERE id = ?""",
+                    (error, elapsed, self._redactions(redacted), row[0]),
+                )
+            else:
+                # PreToolUse was missed (hook added mid-session) — record the failure on its own
+                label, n_label = redact_text(self._extract_tool_label(tool_name, tool_input))
+                input_str, n_input = redact_json(tool_input)
+                conn.execute(
+                    """INSERT INTO tool_event (session_id, tool_name, tool_label, tool_input, tool_use_id, cwd, is_error, error_message,
+                                               redactions, agent_id, created_at)
+                       VALUES (?, ?, ?, ?, ?, ?, 1, ?, ?, ?, ?)""",
+                    (session_id, tool_name, label, input_str, tool_use_id, data.get("cwd", ""), error,
+                     self._redactions(redacted + n_label + n_input), self._tool_agent_id(data), self._now()),
+                )
+            conn.commit()
+        logging.info(f"PostToolUseFailure: {tool_name} {tool_use_id} session={session_id} error={error[:120]!r}")
+        self._emit("PostToolUseFailure", data, f"Failed: {tool_name}", "error", notify=False,
+                   duration=elapsed / 1000 if elapsed is not None else None, message=error)
+
+    @staticmethod
+    def _parent_agent_id(data: dict) -> str | None:
+        """Agent a SubagentStart/Stop fired from — None when the main session spawned it or the payload doesn't say."""
+        parent = data.get("parent_agent_id") or transcript_agent_id(data.get("transcript_path"))
+        return parent if parent and parent != data.get("agent_id") else None
 
     def handle_subagent_start(self, data: dict) -> None:
         agent_id = data.get("agent_id", "")
         agent_type = data.get("agent_type", "") or data.get("subagent_type", "")
         session_id = data.get("session_id", "")
         cwd = data.get("cwd", "")
-        with sqlite3.connect(self.db_path) as conn:
+        parent = self._parent_agent_id(data)
+        with self._connect() as conn:
             conn.execute(
-                """INSERT OR REPLACE INTO agent (agent_id, agent_type, session_id, cwd, started_at, stopped_at)
-                   VALUES (?, ?, ?, ?, CURRENT_TIMESTAMP, NULL)""",
-                (agent_id, agent_type, session_id, cwd),
+                """INSERT OR REPLACE INTO agent (agent_id, agent_type, session_id, cwd, started_at, stopped_at, parent_agent_id)
+                   VALUES (?, ?, ?, ?, ?, NULL, ?)""",
+                (agent_id, agent_type, session_id, cwd, self._now(), parent),
             )
             conn.commit()
-        logging.info(f"Agent started: {agent_type} id={agent_id} session={session_id}")
+        logging.info(f"Agent started: {agent_type} id={agent_id} session={session_id}" + (f" parent={parent}" if parent else ""))
+
+    def _track_usage(self, data: dict, path: str | None = None, agent_id: str | None = None) -> None:
+        """Register a transcript and read any usage written since the last look."""
+        session_id = data.get("session_id", "")
+        path = path or data.get("transcript_path", "")
+        if not session_id or not path or self.replaying:
+            return
+        with self._connect() as conn:
+            track_transcript(conn, path, session_id, agent_id)
+            seen = scan_transcript(conn, path)
+            conn.commit()
+        if seen:
+            logging.info(f"Usage: {seen} message(s) from {os.path.basename(path)}")
 
     def handle_subagent_stop(self, data: dict) -> None:
         agent_id = data.get("agent_id", "")
         agent_type = data.get("agent_type", "") or data.get("subagent_type", "")
         session_id = data.get("session_id", "")
         transcript_path = data.get("agent_transcript_path", "")
-        with sqlite3.connect(self.db_path) as conn:
+        with self._connect() as conn:
             # Update if we tracked the start, otherwise insert a completed record
             conn.execute(
-                """INSERT INTO agent (agent_id, agent_type, session_id, started_at, stopped_at, transcript_path)
-                   VALUES (?, ?, ?, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP, ?)
+                """INSERT INTO agent (agent_id, agent_type, session_id, started_at, stopped_at, transcript_path, parent_agent_id)
+                   VALUES (?, ?, ?, ?, ?, ?, ?)
                    ON CONFLICT(agent_id) DO UPDATE SET
-                       stopped_at = CURRENT_TIMESTAMP,
-                       transcript_path = excluded.transcript_path""",
-                (agent_id, agent_type, session_id, transcript_path),
+                       stopped_at = excluded.stopped_at,
+                       transcript_path = excluded.transcript_path,
+                       parent_agent_id = COALESCE(agent.parent_agent_id, excluded.parent_agent_id)""",
+                (agent_id, agent_type, session_id, self._now(), self._now(), transcript_path, self._parent_agent_id(data)),
             )
             conn.commit()
+            row = conn.execute("SELECT started_at FROM agent WHERE agent_id = ?", (agent_id,)).fetchone()
+        if agent_id and transcript_path:
+            self._track_usage(data, transcript_path, agent_id)
         logging.info(f"Agent stopped: {agent_type} id={agent_id} session={session_id}")
+        elapsed = self._elapsed_ms(row[0]) if row and row[0] else None
+        duration = elapsed / 1000 if elapsed is not None else None
+        label = agent_type or "unknown"
+        self._emit("SubagentStop", data, f"Agent done: {label}" + (f" ({fmt_duration(duration)})" if duration else ""),
+                   "subagent_complete", duration=duration)
 
     def handle_teammate_idle(self, data: dict) -> None:
         session_id = data.get("session_id", "")
@@ -368,35 +2456,59 @@ class ClaudePromptTracker:
         teammate_name = data.get("teammate_name", "")
         cwd = data.get("cwd", "")
         if session_id and team_name:
-            with sqlite3.connect(self.db_path) as conn:
+            with self._connect() as conn:
                 conn.execute(
                     """INSERT OR REPLACE INTO team_session
                            (session_id, team_name, teammate_name, last_seen_at)
-                       VALUES (?, ?, ?, CURRENT_TIMESTAMP)""",
-                    (session_id, team_name, teammate_name),
+                       VALUES (?, ?, ?, ?)""",
+                    (session_id, team_name, teammate_name, self._now()),
                 )
                 conn.commit()
-        iterm = iterm_info()
-        title = iterm["window"] or os.path.basename(cwd)
-        loc = _location_label(iterm)
         label = teammate_name or "teammate"
-        send_notification(title, f"Idle: {label}", loc, "task_complete", cwd)
+        self._emit("TeammateIdle", data, f"Idle: {label}")
         logging.info(f"TeammateIdle: {teammate_name} team={team_name} session={session_id}")
 
     def handle_task_completed(self, data: dict) -> None:
         team_name = data.get("team_name", "")
         task_subject = data.get("task_subject", "task")
         teammate_name = data.get("teammate_name", "")
-        cwd = data.get("cwd", "")
-        iterm = iterm_info()
-        title = iterm["window"] or (f"[{team_name}]" if team_name else os.path.basename(cwd))
         subtitle = f"✓ {task_subject}"
         if teammate_name:
             subtitle += f"  ({teammate_name})"
-        loc = _location_label(iterm)
-        send_notification(title, subtitle, loc, "task_complete", cwd)
+        self._emit("TaskCompleted", data, subtitle, fallback_title=f"[{team_name}]" if team_name else "",
+                   message=task_subject)
         logging.info(f"TaskCompleted: {task_subject!r} team={team_name} teammate={teammate_name}")
 
+    def handle_pre_compact(self, data: dict) -> None:
+        session_id = data.get("session_id")
+        trigger = data.get("trigger", "")
+        instructions, _ = redact_text(data.get("custom_instructions") or "")
+        with self._connect() as conn:
+            conn.execute(
+                """INSERT INTO compaction (session_id, trigger, custom_instructions, created_at)
+                   VALUES (?, ?, ?, ?)""",
+                (session_id, trigger, instructions[:1000] or None, self._now()),
+            )
+            conn.commit()
+        logging.info(f"PreCompact session={session_id} trigger={trigger}")
+
+    def _session_context(self, data: dict) -> dict:
+        """prompt columns for the cwd's git state and the payload's model, permission mode and source."""
+        git = self._git(data.get("cwd", ""))
+        model = data.get("model")
+        if isinstance(model, dict):
+            model = model.get("id") or model.get("display_name")
+        dirty = git.get("dirty")
+        return {
+            "git_root": git.get("root"),
+            "git_branch": git.get("branch"),
+            "git_commit": git.get("commit"),
+            "git_dirty": None if dirty is None else int(dirty),
+            "model": model or None,
+            "permission_mode": data.get("permission_mode") or None,
+            "source": data.get("source") or None,
+        }
+
     def handle_user_prompt_submit(self, data: dict) -> None:
         session_id = data.get("session_id")
         prompt = data.get("prompt", "")
@@ -405,12 +2517,30 @@ class ClaudePromptTracker:
         if prompt.strip().startswith("<"):
             logging.info(f"Skipped system prompt session={session_id}")
             return
-        with sqlite3.connect(self.db_path) as conn:
+        with self._connect() as conn:
+            # Location, model and source are captured at SessionStart; carry them onto each new prompt row
+            row = conn.execute(
+                """SELECT location FROM prompt WHERE session_id = ? AND location IS NOT NULL
+                   ORDER BY id DESC LIMIT 1""",
+                (session_id,),
+            ).fetchone()
+            location = row[0] if row else json.dumps(self._location())
+            prev = conn.execute(
+                "SELECT model, permission_mode, source FROM prompt WHERE session_id = ? ORDER BY id DESC LIMIT 1",
+                (session_id,),
+            ).fetchone() or (None, None, None)
+            ctx = self._session_context(data)
             conn.execute(
-                "INSERT INTO prompt (session_id, prompt, cwd, pid) VALUES (?, ?, ?, ?)",
-                (session_id, prompt, cwd, os.getppid()),
+                """INSERT INTO prompt (session_id, prompt, cwd, pid, location, git_root, git_branch, git_commit, git_dirty,
+                                       model, permission_mode, source, created_at)
+                   VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)""",
+                (session_id, prompt, cwd, self.hook_pid, location, ctx["git_root"], ctx["git_branch"], ctx["git_commit"],
+                 ctx["git_dirty"], ctx["model"] or prev[0], ctx["permission_mode"] or prev[1], ctx["source"] or prev[2],
+                 self._now()),
             )
             conn.commit()
+        # Registered now so maintenance can pick up usage mid-turn
+        self._track_usage(data)
         logging.info(f"Prompt recorded session={session_id}")
 
     def handle_session_start(self, data: dict) -> None:
@@ -419,46 +2549,60 @@ class ClaudePromptTracker:
         cwd = data.get("cwd", "")
         if not session_id:
             return
-        with sqlite3.connect(self.db_path) as conn:
+        with self._connect() as conn:
             # Only insert if no open row already exists for this session
             existing = conn.execute(
-                "SELECT id FROM prompt WHERE session_id = ? AND stoped_at IS NULL LIMIT 1",
+                "SELECT id FROM prompt WHERE session_id = ? AND stopped_at IS NULL LIMIT 1",
                 (session_id,),
             ).fetchone()
+            location = self._location()
+            ctx = self._session_context(data)
             if not existing:
                 conn.execute(
-                    "INSERT INTO prompt (session_id, cwd, pid) VALUES (?, ?, ?)",
-                    (session_id, cwd, os.getppid()),
+                    """INSERT INTO prompt (session_id, cwd, pid, location, git_root, git_branch, git_commit, git_dirty,
+                                           model, permission_mode, source, created_at)
+                       VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)""",
+                    (session_id, cwd, self.hook_pid, json.dumps(location), ctx["git_root"], ctx["git_branch"],
+                     ctx["git_commit"], ctx["git_dirty"], ctx["model"], ctx["permission_mode"], ctx["source"], self._now()),
                 )
-                conn.commit()
-        logging.info(f"Session started session={session_id}")
+            else:
+                # Resumed, cleared or compacted session — the pane, repo state and model may have moved
+                conn.execute(
+                    """UPDATE prompt SET location = ?, git_root = ?, git_branch = ?, git_commit = ?, git_dirty = ?,
+                              model = COALESCE(?, model), permission_mode = COALESCE(?, permission_mode),
+                              source = COALESCE(?, source)
+                       WHERE id = ?""",
+                    (json.dumps(location), ctx["git_root"], ctx["git_branch"], ctx["git_commit"], ctx["git_dirty"],
+                     ctx["model"], ctx["permission_mode"], ctx["source"], existing[0]),
+                )
+            conn.commit()
+        logging.info(f"Session started session={session_id} source={ctx['source']} location={location.get('label', '')!r}")
 
     def handle_session_end(self, data: dict) -> None:
         """Mark session stopped when terminal closes."""
         session_id = data.get("session_id")
         if not session_id:
             return
-        with sqlite3.connect(self.db_path) as conn:
+        with self._connect() as conn:
             conn.execute(
-                "UPDATE prompt SET stoped_at = CURRENT_TIMESTAMP WHERE session_id = ? AND stoped_at IS NULL",
-                (session_id,),
+                "UPDATE prompt SET stopped_at = ? WHERE session_id = ? AND stopped_at IS NULL",
+                (self._now(), session_id),
             )
             conn.execute(
-                "UPDATE agent SET stopped_at = CURRENT_TIMESTAMP WHERE session_id = ? AND stopped_at IS NULL",
-                (session_id,),
+                "UPDATE agent SET stopped_at = ? WHERE session_id = ? AND stopped_at IS NULL",
+                (self._now(), session_id),
             )
             conn.commit()
         logging.info(f"Session ended session={session_id}")
 
     def handle_stop(self, data: dict, is_subagent: bool = False) -> None:
         session_id = data.get("session_id")
-        cwd = data.get("cwd", "")
-        iterm = iterm_info()
+        self._track_usage(data)
 
-        with sqlite3.connect(self.db_path) as conn:
+        with self._connect() as conn:
             cursor = conn.execute(
                 """SELECT id, created_at, cwd FROM prompt
-                   WHERE session_id = ? AND stoped_at IS NULL
+                   WHERE session_id = ? AND stopped_at IS NULL
                    ORDER BY created_at DESC LIMIT 1""",
                 (session_id,),
             )
@@ -467,27 +2611,29 @@ class ClaudePromptTracker:
             if row:
                 record_id = row[0]
                 # Mark session as waiting — NOT stopped. Session stays visible.
-                # Only SessionEnd sets stoped_at (terminal actually closed).
+                # Only SessionEnd sets stopped_at (terminal actually closed).
                 conn.execute(
-                    "UPDATE prompt SET lastWaitUserAt = CURRENT_TIMESTAMP WHERE session_id = ? AND stoped_at IS NULL",
-                    (session_id,),
+                    "UPDATE prompt SET lastWaitUserAt = ? WHERE session_id = ? AND stopped_at IS NULL",
+                    (self._now(), session_id),
                 )
                 conn.commit()
                 seq = conn.execute(
                     "SELECT seq FROM prompt WHERE id = ?", (record_id,),
                 ).fetchone()
                 seq = seq[0] if seq else 1
-                duration = self._duration(record_id)
+                # The turn runs from the prompt until now; stopped_at stays NULL until SessionEnd
+                elapsed = self._elapsed_ms(row[1])
+                duration = elapsed / 1000 if elapsed is not None else None
             else:
                 seq = "?"
-                duration = ""
+                duration = None
 
         # Clean up any running agents for this session — they're done for this turn
-        with sqlite3.connect(self.db_path) as conn:
+        with self._connect() as conn:
             conn.execute(
-                """UPDATE agent SET stopped_at = CURRENT_TIMESTAMP
+                """UPDATE agent SET stopped_at = ?
                    WHERE session_id = ? AND stopped_at IS NULL""",
-                (session_id,),
+                (self._now(), session_id),
             )
             conn.commit()
 
@@ -498,79 +2644,690 @@ class ClaudePromptTracker:
             prefix = "Done"
             sound = "task_complete"
 
-        title = iterm["window"] or os.path.basename(cwd)
-        loc = _location_label(iterm)
-        subtitle = f"{prefix} #{seq}" + (f" ({duration})" if duration else "")
+        subtitle = f"{prefix} #{seq}" + (f" ({fmt_duration(duration)})" if duration else "")
 
-        send_notification(title, subtitle, loc, sound, cwd)
-        logging.info(f"Stop session={session_id} window={iterm['window']!r} win={iterm['window_num']}/{iterm['window_total']} pane={iterm['pane_num']}/{iterm['pane_total']} {prefix} #{seq} {duration}")
+        logging.info(f"Stop session={session_id} {prefix} #{seq} {fmt_duration(duration)}")
+        self._emit("Stop", data, subtitle, sound, duration=duration, seq=seq,
+                   message=data.get("last_assistant_message") or "")
 
     def handle_notification(self, data: dict) -> None:
         session_id = data.get("session_id")
         message = data.get("message", "")
         cwd = data.get("cwd", "")
-        iterm = iterm_info()
         msg_lower = message.lower()
 
-        title = iterm["window"] or os.path.basename(cwd)
-        loc = _location_label(iterm)
-
-        logging.info(f"Notification session={session_id} window={iterm['window']!r} win={iterm['window_num']}/{iterm['window_total']} pane={iterm['pane_num']}/{iterm['pane_total']} message={message!r}")
+        logging.info(f"Notification session={session_id} cwd={cwd!r} message={message!r}")
 
         if "waiting for your input" in msg_lower or "waiting for input" in msg_lower:
             # Suppress if Stop already fired for this session's latest prompt.
             # The user already got a "Done" notification — no need to nag again.
-            with sqlite3.connect(self.db_path) as conn:
+            with self._connect() as conn:
                 row = conn.execute(
-                    """SELECT stoped_at FROM prompt
+                    """SELECT stopped_at FROM prompt
                        WHERE session_id = ?
                        ORDER BY created_at DESC LIMIT 1""",
                     (session_id,),
                 ).fetchone()
                 if row and row[0] is not None:
+                    if not self.replaying:
+                        title, _ = self._notify_context(cwd)
+                        self._record_notification(conn, {"event": "Notification", "session_id": session_id, "cwd": cwd},
+                                                  title, "Waiting for input", "waiting_input", "suppressed",
+                                                  "session already stopped")
+                        conn.commit()
                     logging.info(f"Suppressed 'waiting for input' — Stop already fired for session={session_id}")
                     return
 
                 conn.execute("""
-                    UPDATE prompt SET lastWaitUserAt = CURRENT_TIMESTAMP
+                    UPDATE prompt SET lastWaitUserAt = ?
                     WHERE id = (
                         SELECT id FROM prompt WHERE session_id = ?
                         ORDER BY created_at DESC LIMIT 1
-                    )""", (session_id,))
+                    )""", (self._now(), session_id))
                 conn.commit()
-            send_notification(title, "Waiting for input", loc, "waiting_input", cwd)
+            subtitle, sound = "Waiting for input", "waiting_input"
         elif "permission" in msg_lower:
-            send_notification(title, "Permission required", loc, "permission", cwd)
+            subtitle, sound = "Permission required", "permission"
         elif "error" in msg_lower or "failed" in msg_lower:
-            send_notification(title, "Error", loc, "error", cwd)
+            subtitle, sound = "Error", "error"
         elif "approval" in msg_lower or "choose an option" in msg_lower:
-            send_notification(title, "Action required", loc, "waiting_input", cwd)
+            subtitle, sound = "Action required", "waiting_input"
         else:
-            send_notification(title, "Notification", loc, "task_complete", cwd)
+            subtitle, sound = "Notification", "task_complete"
+        if subtitle in WAIT_KINDS and session_id:
+            self._open_wait(session_id, WAIT_KINDS[subtitle], message)
+        self._emit("Notification", data, subtitle, sound)
 
-    def _duration(self, record_id: int) -> str:
-        with sqlite3.connect(self.db_path) as conn:
-            row = conn.execute(
-                "SELECT created_at, stoped_at FROM prompt WHERE id = ?",
-                (record_id,),
-            ).fetchone()
-        if not row or not row[1]:
-            return ""
-        try:
-            start = datetime.fromisoformat(row[0]).replace(tzinfo=timezone.utc)
-            end = datetime.fromisoformat(row[1]).replace(tzinfo=timezone.utc)
-            secs = int((end - start).total_seconds())
-            if secs < 60:
-                return f"{secs}s"
-            elif secs < 3600:
-                m, s = divmod(secs, 60)
-                return f"{m}m{s}s" if s else f"{m}m"
+    def _open_wait(self, session_id: str, kind: str, message: str) -> None:
+        """Start a wait for the session, or update the one already open with the latest prompt."""
+        message, _ = redact_text(message)
+        with self._connect() as conn:
+            if not conn.execute(
+                "UPDATE wait SET kind = ?, message = ? WHERE session_id = ? AND ended_at IS NULL",
+                (kind, message, session_id),
+            ).rowcount:
+                conn.execute(
+                    "INSERT INTO wait (session_id, kind, message, started_at) VALUES (?, ?, ?, ?)",
+                    (session_id, kind, message, self._now()),
+                )
+            set_session_state(conn, session_id, WAIT_STATES[kind], "Notification", self._now())
+            conn.commit()
+
+    def _advance_session(self, event: str, session_id: str) -> None:
+        """The session moved on: close its open wait and move it to the state this event implies."""
+        with self._connect() as conn:
+            if event != "Notification":
+                conn.execute(
+                    """UPDATE wait SET ended_at = ?,
+                                       duration_ms = CAST((julianday(?) - julianday(started_at)) * 86400000 AS INTEGER)
+                       WHERE session_id = ? AND ended_at IS NULL""",
+                    (self._now(), self._now(), session_id),
+                )
+            if event in EVENT_STATES:
+                set_session_state(conn, session_id, EVENT_STATES[event], event, self._now())
+            conn.commit()
+
+    def _location(self) -> dict:
+        """Pane that fired the hook — detected once per event, or as recorded in the log on rebuild."""
+        if self.event_location is None:
+            if self._batch is not None:
+                # The collector never shells out under the write lock: use the session's recorded pane
+                with self._connect() as conn:
+                    row = conn.execute(
+                        """SELECT location FROM prompt WHERE session_id = ? AND location IS NOT NULL
+                           ORDER BY id DESC LIMIT 1""",
+                        (self.event_session,),
+                    ).fetchone()
+                self.event_location = json.loads(row[0]) if row else {}
             else:
-                h, remainder = divmod(secs, 3600)
-                m = remainder // 60
-                return f"{h}h{m}m" if m else f"{h}h"
-        except Exception:
-            return ""
+                self.event_location = detect_location(self.hook_env)
+        return self.event_location
+
+    def _effect(self, fn, *args) -> None:
+        """Run something with outside effects (a banner, a sound, a command) now, or after the batch commits."""
+        if self._outbox is not None:
+            self._outbox.append((fn, args))
+        else:
+            fn(*args)
+
+    def _git(self, cwd: str) -> dict:
+        """Git state of the event's cwd — looked up once per event, or as recorded in the log on rebuild."""
+        if self.event_git is None:
+            self.event_git = git_context(cwd)
+        return self.event_git
+
+    def log_event(self, event: str, data: dict) -> None:
+        """Append the payload (redacted, untruncated) to the event log before any handler sees it."""
+        payload, _ = redact_json(data, limit=None)
+        env = {k: self.hook_env[k] for k in LOCATION_ENV_KEYS if k in self.hook_env}
+        # The pane and repo are only looked up where a handler stores them, so rebuild doesn't re-query them
+        location = json.dumps(self._location()) if event == "SessionStart" else None
+        git = json.dumps(self._git(data.get("cwd", ""))) if wants_git(event, data) else None
+        with self._connect() as conn:
+            self.event_id = conn.execute(
+                """INSERT INTO event (event, session_id, payload, pid, env, location, git, created_at)
+                   VALUES (?, ?, ?, ?, ?, ?, ?, ?)""",
+                (event, data.get("session_id"), payload, self.hook_pid, json.dumps(env), location, git,
+                 datetime.now(timezone.utc).strftime("%Y-%m-%d %H:%M:%S.%f")[:23]),  # ms, so replayed durations match
+            ).lastrowid
+            conn.commit()
+
+    def _redactions(self, counted: int) -> int:
+        """Secrets a handler masked: counted now, or the logged count on rebuild (the log is already redacted)."""
+        if self.replaying:
+            return self.event_redactions
+        self.event_redactions += counted
+        return counted
+
+    def dispatch(self, event: str, data: dict, git: dict | None = None, location: dict | None = None) -> None:
+        """Log one hook payload and route it to its handler.
+
+        `git` and `location` are the repo state and pane the hook already
+        looked up, so the collector never shells out while it holds the
+        write lock.
+        """
+        self.event_session = data.get("session_id") or ""
+        if not self.replaying:
+            self.event_location = location
+            self.event_git = git
+            self.event_redactions = 0
+            self.log_event(event, data)
+        # Anything the session itself does (not a subagent) means nobody is waiting on the user anymore
+        if data.get("session_id") and not self._tool_agent_id(data):
+            self._advance_session(event, data["session_id"])
+        if event == "SessionStart":
+            self.handle_session_start(data)
+        elif event == "SessionEnd":
+            self.handle_session_end(data)
+        elif event == "UserPromptSubmit":
+            self.handle_user_prompt_submit(data)
+        elif event == "Stop":
+            self.handle_stop(data, is_subagent=False)
+        elif event == "SubagentStart":
+            self.handle_subagent_start(data)
+        elif event == "SubagentStop":
+            # Notifies on its own — handle_stop would prematurely close the
+            # parent session's prompt row.
+            self.handle_subagent_stop(data)
+        elif event == "Notification":
+            self.handle_notification(data)
+        elif event == "PreToolUse":
+            self.handle_pre_tool_use(data)
+        elif event == "PostToolUse":
+            self.handle_post_tool_use(data)
+        elif event == "PostToolUseFailure":
+            self.handle_post_tool_use_failure(data)
+        elif event == "TeammateIdle":
+            self.handle_teammate_idle(data)
+        elif event == "TaskCompleted":
+            self.handle_task_completed(data)
+        elif event == "PreCompact":
+            self.handle_pre_compact(data)
+        else:
+            # Hook types this version doesn't know yet: the event log row is the record
+            logging.info(f"{event}: stored unhandled event session={data.get('session_id')}")
+        if self.event_redactions and not self.replaying:
+            with self._connect() as conn:
+                conn.execute("UPDATE event SET redactions = ? WHERE id = ?", (self.event_redactions, self.event_id))
+                conn.commit()
+
+    def _notify_context(self, cwd: str, fallback_title: str = "") -> tuple[str, str]:
+        """Notification title and location line for the pane that fired the hook."""
+        location = self._location()
+        return location.get("window") or fallback_title or os.path.basename(cwd), location.get("label", "")
+
+    def _emit(self, event: str, data: dict, subtitle: str, sound: str = "task_complete",
+              notify: bool = True, fallback_title: str = "", **fields) -> None:
+        """Describe an event to the notification rules and carry out what they decide.
+
+        `notify` is the built-in behaviour when no rule matches; tool events pass
+        False so they only ever notify through an explicit rule. Unless the caller
+        passes a title, the pane is only looked up once an action needs one.
+        """
+        if self.replaying or (not notify and not load_rules()):
+            return
+        cwd = data.get("cwd", "")
+        ctx = {
+            "event": event,
+            "session_id": data.get("session_id", ""),
+            "cwd": cwd,
+            "project": os.path.basename(cwd.rstrip("/")),
+            "agent_type": data.get("agent_type") or data.get("subagent_type") or "",
+            "tool": data.get("tool_name", ""),
+            "message": data.get("message", ""),
+            "duration": None,
+            "subtitle": subtitle,
+            "sound": sound,
+        }
+        ctx.update(fields)
+        if ctx["duration"] is not None:
+            ctx["elapsed"] = fmt_duration(ctx["duration"])
+        actions = evaluate_rules(ctx)
+        if actions is None:
+            if not notify:
+                return
+            actions = [{"notify": True}]
+        if "title" in ctx:
+            ctx.setdefault("loc", "")
+        elif any(a.get("notify") or a.get("run") for a in actions):
+            ctx["title"], ctx["loc"] = self._notify_context(cwd, fallback_title)
+        else:
+            ctx["title"], ctx["loc"] = fallback_title or os.path.basename(cwd), ""
+        if perform_actions(actions, ctx, self._deliver, self._effect):
+            with self._connect() as conn:
+                self._record_notification(conn, ctx, ctx["title"], ctx["subtitle"], ctx["sound"],
+                                          "suppressed", f"rule {ctx.get('rule', '')}")
+                conn.commit()
+
+    @staticmethod
+    def _record_notification(conn, ctx: dict, title: str, subtitle: str, kind: str | None,
+                             status: str, reason: str = "", queue_id: int | None = None) -> int:
+        """Add a row to the notification history; kind is the sound key (waiting_input, error, ...)."""
+        return conn.execute(
+            """INSERT INTO notification (session_id, cwd, event, title, subtitle, kind, status, reason, queue_id)
+               VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)""",
+            (ctx.get("session_id", ""), ctx.get("cwd", ""), ctx.get("event", ""), title, subtitle,
+             kind, status, reason, queue_id),
+        ).lastrowid
+
+    def _deliver(self, ctx: dict, title: str, subtitle: str, sound_key: str | None, urgent: bool = False) -> None:
+        """Send a notification now, or hold it for a summary, the rate limit or the end of quiet hours."""
+        cfg = notify_config()
+        now = time.time()
+        event = ctx.get("event", "")
+        session_id = ctx.get("session_id", "")
+        kind, due, spawn, held = "direct", None, False, []
+        with self._connect() as conn:
+            quiet_end = None if urgent else quiet_until(now)
+            if quiet_end is not None:
+                if not cfg["quiet_hours"].get("digest", True):
+                    self._record_notification(conn, ctx, title, subtitle, sound_key, "dropped", "quiet hours")
+                    conn.commit()
+                    logging.info(f"Quiet hours: dropped {title} | {subtitle}")
+                    return
+                kind, due = "quiet", quiet_end
+            elif not urgent and event in COALESCE_EVENTS and cfg.get("coalesce_window"):
+                pending = conn.execute(
+                    "SELECT MIN(due_at) FROM notify_queue WHERE session_id = ? AND kind = 'batch' AND sent_at IS NULL",
+                    (session_id,),
+                ).fetchone()[0]
+                kind, due, spawn = "batch", pending or now + float(cfg["coalesce_window"]), pending is None
+            elif not urgent:
+                limit = cfg.get("rate_limit") or {}
+                per_session, window = limit.get("per_session"), float(limit.get("window") or 60)
+                if per_session:
+                    # Summaries mark all their rows with one sent_at, so count sends, not rows
+                    sent, oldest = conn.execute(
+                        "SELECT COUNT(DISTINCT sent_at), MIN(sent_at) FROM notify_queue WHERE session_id = ? AND sent_at >= ?",
+                        (session_id, now - window),
+                    ).fetchone()
+                    if sent >= int(per_session):
+                        pending = conn.execute(
+                            "SELECT MIN(due_at) FROM notify_queue WHERE session_id = ? AND kind = 'rate' AND sent_at IS NULL",
+                            (session_id,),
+                        ).fetchone()[0]
+                        kind, due, spawn = "rate", pending or oldest + window, pending is None
+            if kind == "direct" and event == "Stop":
+                # The turn is over — fold completions still waiting for their window into "Done"
+                held = conn.execute(
+                    """SELECT id, event, subtitle FROM notify_queue
+                       WHERE session_id = ? AND kind IN ('batch', 'rate') AND sent_at IS NULL""",
+                    (session_id,),
+                ).fetchall()
+                if held:
+                    subtitle += " · " + summarize([(e, st) for _, e, st in held])
+                    conn.executemany("UPDATE notify_queue SET sent_at = ? WHERE id = ?", [(now, r[0]) for r in held])
+            queue_id = conn.execute(
+                """INSERT INTO notify_queue (session_id, cwd, event, title, subtitle, loc, sound, kind, due_at, sent_at)
+                   VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)""",
+                (session_id, ctx.get("cwd", ""), event, title, subtitle, ctx.get("loc", ""), sound_key,
+                 kind, due, now if kind == "direct" else None),
+            ).lastrowid
+            if kind == "direct":
+                notif_id = self._record_notification(conn, ctx, title, subtitle, sound_key, "delivered",
+                                                     "urgent" if urgent else "", queue_id)
+                if held:
+                    self._summarised(conn, [r[0] for r in held], notif_id)
+            else:
+                self._record_notification(conn, ctx, title, subtitle, sound_key, "held", HELD_REASONS[kind], queue_id)
+            conn.commit()
+        if kind == "direct":
+            self._effect(send_notification, title, subtitle, ctx.get("loc", ""), sound_key, ctx.get("cwd"))
+            return
+        logging.info(f"Held ({kind}, {due - now:.0f}s): {title} | {subtitle}")
+        if spawn:
+            self._schedule_flush(due - now)
+
+    @staticmethod
+    def _summarised(conn, queue_ids: list[int], notif_id: int) -> None:
+        """Mark held notifications as sent as part of notification #notif_id."""
+        conn.executemany(
+            "UPDATE notification SET status = 'summarised', reason = ? WHERE queue_id = ?",
+            [(f"in #{notif_id}", qid) for qid in queue_ids],
+        )
+
+    def _schedule_flush(self, delay: float) -> None:
+        """In hook mode, leave a detached process behind to send the summary when it is due."""
+        if self._batch is not None:
+            return  # the collector's writer loop flushes
+        try:
+            subprocess.Popen(
+                [sys.executable, os.path.abspath(__file__), "flush", f"{max(delay, 0):.2f}"],
+                stdin=subprocess.DEVNULL, stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL,
+                start_new_session=True,
+            )
+        except Exception as e:
+            logging.error(f"Could not schedule notification flush: {e}")
+
+    def flush_notifications(self) -> int:
+        """Send held notifications that are due; returns how many banners went out."""
+        now = time.time()
+        with self._connect() as conn:
+            if not conn.execute(
+                "SELECT 1 FROM notify_queue WHERE sent_at IS NULL AND due_at <= ? LIMIT 1", (now,),
+            ).fetchone():
+                return 0
+            if self._batch is None:
+                conn.execute("BEGIN IMMEDIATE")  # another hook may be flushing the same rows
+            rows = conn.execute(
+                """SELECT id, session_id, cwd, event, title, subtitle, loc, sound, kind FROM notify_queue
+                   WHERE sent_at IS NULL AND due_at <= ? ORDER BY id""",
+                (now,),
+            ).fetchall()
+            if not rows:
+                conn.commit()
+                return 0
+            quiet_end = quiet_until(now)
+            if quiet_end is not None:
+                # Came due inside quiet hours — it joins the digest instead
+                conn.execute(
+                    "UPDATE notify_queue SET kind = 'quiet', due_at = ? WHERE sent_at IS NULL AND due_at <= ?",
+                    (quiet_end, now),
+                )
+                conn.executemany("UPDATE notification SET reason = ? WHERE queue_id = ?",
+                                 [(HELD_REASONS["quiet"], r[0]) for r in rows])
+                conn.commit()
+                return 0
+            conn.executemany("UPDATE notify_queue SET sent_at = ? WHERE id = ?", [(now, r[0]) for r in rows])
+            outgoing = []
+            quiet = [r for r in rows if r[8] == "quiet"]
+            if quiet:
+                projects: dict[str, list] = {}
+                for r in quiet:
+                    projects.setdefault(os.path.basename((r[2] or "").rstrip("/")) or "?", []).append((r[3], r[5]))
+                detail = "; ".join(f"{p}: {summarize(items)}" for p, items in projects.items())
+                digest = ("Quiet hours digest", _plural(len(quiet), ("notification", "notifications")))
+                notif_id = self._record_notification(conn, {"event": "Digest"}, *digest, "task_complete",
+                                                     "delivered", f"digest of {len(quiet)}")
+                self._summarised(conn, [r[0] for r in quiet], notif_id)
+                outgoing.append((*digest, detail, "task_complete", None))
+            sessions: dict[str, list] = {}
+            for r in rows:
+                if r[8] != "quiet":
+                    sessions.setdefault(r[1], []).append(r)
+            for session_id, group in sessions.items():
+                last = group[-1]
+                if len(group) == 1:
+                    conn.execute("UPDATE notification SET status = 'delivered' WHERE queue_id = ?", (last[0],))
+                    outgoing.append((last[4], last[5], last[6], last[7], last[2]))
+                    continue
+                project = os.path.basename((last[2] or "").rstrip("/")) or "session"
+                subtitle = f"{summarize([(r[3], r[5]) for r in group])} in {project}"
+                if any(r[3] == "SubagentStop" for r in group):
+                    running = conn.execute(
+                        "SELECT COUNT(*) FROM agent WHERE session_id = ? AND stopped_at IS NULL", (session_id,),
+                    ).fetchone()[0]
+                    if running:
+                        subtitle += f", {running} still running"
+                notif_id = self._record_notification(
+                    conn, {"event": "Summary", "session_id": session_id, "cwd": last[2]},
+                    last[4], subtitle, group[0][7], "delivered", f"summary of {len(group)}",
+                )
+                self._summarised(conn, [r[0] for r in group], notif_id)
+                outgoing.append((last[4], subtitle, last[6], group[0][7], last[2]))
+            conn.commit()
+        for title, subtitle, message, sound_key, cwd in outgoing:
+            send_notification(title, subtitle, message or "", sound_key, cwd)
+        return len(outgoing)
+
+
+# ── COLLECTOR ────────────────────────────────────────────────
+# Optional long-lived process (`ccnotify.py collector`). Hooks forward their
+# payload over a Unix socket instead of opening the database themselves; one
+# writer thread applies them in batches and runs maintenance on a timer.
+
+COLLECTOR_DEFAULTS = {
+    "socket": os.path.join(SCRIPT_DIR, "collector.sock"),
+    "flush_ms": 250,             # max delay before a batch is written
+    "maintenance_interval": 60,  # seconds between maintenance runs
+    # Events a failed writer could not save; written on the next start
+    "dead_letter": os.path.join(SCRIPT_DIR, "collector.dead.jsonl"),
+}
+# Without a collector, hooks take turns running maintenance at most this often.
+HOOK_MAINTENANCE_INTERVAL = 600
+# Tries per batch before the writer gives up and hands writes back to the hooks
+COLLECTOR_WRITE_ATTEMPTS = 3
+
+
+def collector_config() -> dict:
+    cfg = dict(COLLECTOR_DEFAULTS)
+    user = load_config().get("collector", {})
+    if isinstance(user, dict):
+        cfg.update(user)
+    cfg["socket"] = os.path.expanduser(cfg["socket"])
+    cfg["dead_letter"] = os.path.expanduser(cfg["dead_letter"])
+    return cfg
+
+
+def forward_to_collector(envelope: dict) -> bool:
+    """Hand a hook payload to a running collector. False means: write it yourself."""
+    path = collector_config()["socket"]
+    if not os.path.exists(path):
+        return False
+    try:
+        with socket.socket(socket.AF_UNIX, socket.SOCK_STREAM) as sock:
+            sock.settimeout(0.5)
+            sock.connect(path)
+            sock.sendall(json.dumps(envelope, default=str).encode() + b"\n")
+            sock.shutdown(socket.SHUT_WR)
+            return sock.recv(16).startswith(b"ok")
+    except OSError:
+        return False
+
+
+def forward_probe(path: str) -> bool:
+    """True if something is accepting connections on this socket."""
+    try:
+        with socket.socket(socket.AF_UNIX, socket.SOCK_STREAM) as sock:
+            sock.settimeout(0.5)
+            sock.connect(path)
+        return True
+    except OSError:
+        return False
+
+
+class Collector:
+    def __init__(self, tracker: ClaudePromptTracker):
+        self.tracker = tracker
+        self.cfg = collector_config()
+        self.queue: queue.Queue = queue.Queue()
+        self.stopping = threading.Event()
+        self.failed = threading.Event()  # writer is gone: stop acking so hooks write directly
+        self.accepting = threading.Lock()  # a payload is either queued before `failed` or refused
+        self.inflight: list[dict] = []     # batch the writer is working on
+
+    def _accept(self, server: socket.socket) -> None:
+        while not self.stopping.is_set():
+            try:
+                client, _ = server.accept()
+            except OSError:
+                break
+            threading.Thread(target=self._read_client, args=(client,), daemon=True).start()
+
+    def _read_client(self, client: socket.socket) -> None:
+        with client:
+            client.settimeout(5)
+            buf = b""
+            try:
+                while chunk := client.recv(65536):
+                    buf += chunk
+                envelope = json.loads(buf)
+                if not isinstance(envelope, dict) or not isinstance(envelope.get("data"), dict):
+                    raise ValueError("bad envelope")
+            except Exception as e:
+                logging.error(f"Collector: dropped payload: {e}")
+                return
+            with self.accepting:
+                if self.failed.is_set():
+                    return  # no "ok", so the hook writes the event itself
+                self.queue.put(envelope)
+            try:
+                client.sendall(b"ok\n")
+            except OSError:
+                pass
+
+    def _apply_batch(self, conn: sqlite3.Connection, batch: list[dict]) -> list[tuple]:
+        """Write a batch in one transaction; returns the effects its handlers held back."""
+        self.tracker._batch = _BatchConnection(conn)
+        self.tracker._outbox = outbox = []
+        try:
+            conn.execute("BEGIN IMMEDIATE")
+            for env in batch:
+                self.tracker.hook_pid = env.get("pid") or 0
+                self.tracker.hook_env = env.get("env") or {}
+                held = len(outbox)
+                conn.execute("SAVEPOINT hook_event")
+                try:
+                    self.tracker.dispatch(env.get("event", ""), env["data"], env.get("git"), env.get("location"))
+                    conn.execute("RELEASE hook_event")
+                except Exception as e:
+                    conn.execute("ROLLBACK TO hook_event")
+                    conn.execute("RELEASE hook_event")
+                    del outbox[held:]
+                    logging.exception(f"Collector: {env.get('event')} failed: {e}")
+            conn.execute("COMMIT")
+        except BaseException:
+            if conn.in_transaction:
+                conn.execute("ROLLBACK")
+            raise
+        finally:
+            self.tracker._batch = None
+            self.tracker._outbox = None
+        return outbox
+
+    def _write(self, conn: sqlite3.Connection, batch: list[dict]) -> None:
+        """Apply a batch, retrying with a growing pause while the database stays locked.
+
+        Notifications go out only after the commit, so a retried batch never
+        sends one twice and a failed one sends none.
+        """
+        for attempt in range(1, COLLECTOR_WRITE_ATTEMPTS + 1):
+            try:
+                outbox = self._apply_batch(conn, batch)
+                break
+            except sqlite3.Error as e:
+                if attempt == COLLECTOR_WRITE_ATTEMPTS:
+                    raise
+                logging.warning(f"Collector: batch of {len(batch)} event(s) failed ({e}), retrying")
+                time.sleep(attempt)
+        for fn, args in outbox:
+            try:
+                fn(*args)
+            except Exception as e:
+                logging.exception(f"Collector: {fn.__name__} failed: {e}")
+
+    def _writer(self) -> None:
+        try:
+            self._write_loop()
+        except Exception as e:
+            logging.exception(f"Collector: writer stopped: {e}")
+            with self.accepting:
+                # Unlink so new hooks write directly instead of queueing for nobody
+                self.failed.set()
+                try:
+                    os.unlink(self.cfg["socket"])
+                except OSError:
+                    pass
+                pending = self.inflight
+                while not self.queue.empty():
+                    pending.append(self.queue.get_nowait())
+            self._salvage(pending)
+            self.stopping.set()
+
+    def _salvage(self, pending: list[dict]) -> None:
+        """Save events the hooks were told were written: on a fresh connection, else to the dead-letter file."""
+        if not pending:
+            return
+        try:
+            conn = sqlite3.connect(self.tracker.db_path, isolation_level=None, timeout=30)
+            try:
+                self._write(conn, pending)
+            finally:
+                conn.close()
+            logging.info(f"Collector: wrote {len(pending)} pending event(s) on a fresh connection")
+            return
+        except Exception as e:
+            logging.error(f"Collector: could not write {len(pending)} pending event(s): {e}")
+        path = self.cfg["dead_letter"]
+        try:
+            with open(path, "a", encoding="utf-8") as f:
+                for env in pending:
+                    f.write(json.dumps(env, default=str) + "\n")
+            logging.error(f"Collector: saved {len(pending)} event(s) to {path}; they are written on the next start")
+        except OSError as e:
+            logging.error(f"Collector: lost {len(pending)} event(s), dead-letter file unwritable: {e}")
+
+    def _load_dead_letters(self) -> None:
+        """Queue events a previous collector could not write, ahead of anything new."""
+        path = self.cfg["dead_letter"]
+        try:
+            with open(path, encoding="utf-8") as f:
+                lines = f.readlines()
+            os.unlink(path)
+        except OSError:
+            return
+        for line in lines:
+            try:
+                env = json.loads(line)
+            except ValueError:
+                continue
+            if isinstance(env, dict) and isinstance(env.get("data"), dict):
+                self.queue.put(env)
+        logging.info(f"Collector: replaying {self.queue.qsize()} event(s) from {path}")
+
+    def _write_loop(self) -> None:
+        conn = sqlite3.connect(self.tracker.db_path, isolation_level=None, timeout=30)
+        try:
+            self._drain(conn)
+        finally:
+            conn.close()
+
+    def _drain(self, conn: sqlite3.Connection) -> None:
+        flush = self.cfg["flush_ms"] / 1000
+        next_maintenance = time.time()
+        while not (self.stopping.is_set() and self.queue.empty()):
+            batch = []
+            try:
+                batch.append(self.queue.get(timeout=flush))
+                while True:
+                    batch.append(self.queue.get_nowait())
+            except queue.Empty:
+                pass
+            if batch:
+                self.inflight = batch
+                self._write(conn, batch)
+                self.inflight = []
+                logging.info(f"Collector: wrote {len(batch)} event(s)")
+            self.tracker._batch = _BatchConnection(conn)
+            try:
+                self.tracker.flush_notifications()
+            except Exception as e:
+                logging.exception(f"Collector: notification flush failed: {e}")
+            finally:
+                self.tracker._batch = None
+            if time.time() >= next_maintenance:
+                self.tracker._batch = _BatchConnection(conn)
+                try:
+                    self.tracker.maintenance()
+                except Exception as e:
+                    logging.exception(f"Collector: maintenance failed: {e}")
+                finally:
+                    self.tracker._batch = None
+                next_maintenance = time.time() + self.cfg["maintenance_interval"]
+
+    def serve(self) -> None:
+        path = self.cfg["socket"]
+        if os.path.exists(path):
+            if forward_probe(path):
+                print(f"collector already running on {path}", file=sys.stderr)
+                sys.exit(1)
+            os.unlink(path)  # stale socket from a crashed collector
+        server = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+        server.bind(path)
+        os.chmod(path, 0o600)
+        server.listen(64)
+        self._load_dead_letters()
+        writer = threading.Thread(target=self._writer)
+        writer.start()
+        threading.Thread(target=self._accept, args=(server,), daemon=True).start()
+
+        def stop(*_):
+            self.stopping.set()
+        signal.signal(signal.SIGTERM, stop)
+        signal.signal(signal.SIGINT, stop)
+        logging.info(f"Collector listening on {path}")
+        try:
+            while not self.stopping.wait(1):
+                pass
+        finally:
+            # Unlink first so new hooks fall back to direct writes while we drain
+            try:
+                os.unlink(path)
+            except OSError:
+                pass
+            server.close()
+            writer.join()
+            logging.info("Collector stopped")
 
 
 def main():
@@ -578,9 +3335,17 @@ def main():
         return
 
     event = sys.argv[1]
-    valid = ["SessionStart", "SessionEnd", "UserPromptSubmit", "Stop", "SubagentStart", "SubagentStop",
-             "Notification", "PreToolUse", "PostToolUse", "TeammateIdle", "TaskCompleted"]
-    if event not in valid:
+    if event == "collector":
+        Collector(ClaudePromptTracker()).serve()
+        return
+    if event == "flush":
+        # Spawned by a hook that held a notification back; sends it once it is due
+        time.sleep(float(sys.argv[2]) if len(sys.argv) > 2 else 0)
+        ClaudePromptTracker().flush_notifications()
+        return
+
+    # Any hook name is accepted so new Claude Code events land in the event log
+    if not re.fullmatch(r"[A-Za-z][A-Za-z0-9]*", event):
         logging.error(f"Invalid event: {event}")
         sys.exit(1)
 
@@ -594,39 +3359,25 @@ def main():
         logging.error(f"JSON parse error: {e}")
         sys.exit(1)
 
-    tracker = ClaudePromptTracker()
-
-    if event == "SessionStart":
-        tracker.handle_session_start(data)
-    elif event == "SessionEnd":
-        tracker.handle_session_end(data)
-    elif event == "UserPromptSubmit":
-        tracker.handle_user_prompt_submit(data)
-    elif event == "Stop":
-        tracker.handle_stop(data, is_subagent=False)
-    elif event == "SubagentStart":
-        tracker.handle_subagent_start(data)
-    elif event == "SubagentStop":
-        tracker.handle_subagent_stop(data)
-        # Send notification but do NOT call handle_stop — that would
-        # prematurely close the parent session's prompt row.
-        agent_type = data.get("agent_type", "unknown")
-        cwd = data.get("cwd", "")
-        iterm = iterm_info()
-        title = iterm["window"] or os.path.basename(cwd)
-        loc = _location_label(iterm)
-        send_notification(title, f"Agent done: {agent_type}", loc, "subagent_complete", cwd)
-    elif event == "Notification":
-        tracker.handle_notification(data)
-    elif event == "PreToolUse":
-        tracker.handle_pre_tool_use(data)
-    elif event == "PostToolUse":
-        tracker.handle_post_tool_use(data)
-    elif event == "TeammateIdle":
-        tracker.handle_teammate_idle(data)
-    elif event == "TaskCompleted":
-        tracker.handle_task_completed(data)
+    envelope = {
+        "event": event,
+        "data": data,
+        "pid": os.getppid(),
+        "env": {k: os.environ[k] for k in LOCATION_ENV_KEYS if k in os.environ},
+    }
+    # Looked up here rather than in the collector's write transaction
+    if isinstance(data, dict) and wants_git(event, data):
+        envelope["git"] = git_context(data.get("cwd", ""))
+    forwarding = os.path.exists(collector_config()["socket"])
+    if forwarding and isinstance(data, dict) and wants_location(event, data):
+        envelope["location"] = detect_location()
+    if forwarding and forward_to_collector(envelope):
+        return
 
+    tracker = ClaudePromptTracker()
+    tracker.dispatch(event, data, envelope.get("git"), envelope.get("location"))
+    tracker.flush_notifications()
+    tracker.maybe_maintenance(HOOK_MAINTENANCE_INTERVAL)
 
 if __name__ == "__main__":
     main()
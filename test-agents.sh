@@ -61,6 +61,6 @@ for i in $(seq 1 "$COUNT"); do
 done
 
 wait
-sqlite3 "$DB" "UPDATE prompt SET stoped_at = CURRENT_TIMESTAMP WHERE session_id = '$SESSION_ID';"
+sqlite3 "$DB" "UPDATE prompt SET stopped_at = CURRENT_TIMESTAMP WHERE session_id = '$SESSION_ID';"
 echo ""
 echo "done."
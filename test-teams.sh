@@ -26,7 +26,7 @@ echo ""
 cleanup() {
     echo "cleaning up..."
     # Mark teammate sessions as stopped
-    sqlite3 "$DB" "UPDATE prompt SET stoped_at = CURRENT_TIMESTAMP WHERE session_id IN ('$SESSION_RESEARCHER','$SESSION_IMPLEMENTER','$SESSION_REVIEWER');" 2>/dev/null || true
+    sqlite3 "$DB" "UPDATE prompt SET stopped_at = CURRENT_TIMESTAMP WHERE session_id IN ('$SESSION_RESEARCHER','$SESSION_IMPLEMENTER','$SESSION_REVIEWER');" 2>/dev/null || true
     # Remove team config + tasks
     rm -rf "$TEAMS_DIR" "$TASKS_DIR"
     echo "done."